@@ -9,5 +9,4 @@ fn main() {
         .compile("audio_engine");
 
     println!("cargo::rustc-link-lib=framework=AudioUnit");
-    println!("cargo::rustc-link-lib=framework=ApplicationServices");
 }
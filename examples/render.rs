@@ -0,0 +1,45 @@
+use std::sync::mpsc;
+
+use synth::midi::ChannelMap;
+use synth::osc::Waveform;
+use synth::preset::{self, Instrument};
+use synth::smf::Smf;
+use synth::{Synth, wav};
+
+const SAMPLE_RATE: f64 = 44_100.0;
+
+// cargo run --example render -- song.mid song.wav
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (Some(input), Some(output)) = (args.next(), args.next()) else {
+        eprintln!("usage: render <input.mid> <output.wav>");
+        std::process::exit(1);
+    };
+
+    let smf = Smf::load(&input).expect("failed to load midi file");
+    println!(
+        "format {}, {} tracks, {} events, {:.1}s",
+        smf.format,
+        smf.tracks,
+        smf.events.len(),
+        smf.duration()
+    );
+
+    let lead = Instrument::builder()
+        .osc(Waveform::Sine, 1.0)
+        .osc(Waveform::Saw, 0.2)
+        .env(0.002, 0.1, 0.8, 0.2)
+        .build();
+
+    let instruments = vec![lead, preset::kick(), preset::snare(), preset::hihat()];
+
+    let (_tx, rx) = mpsc::channel();
+    let mut synth = Synth::<64>::new(SAMPLE_RATE, rx, instruments);
+
+    // Melodic channels on the lead, GM drum channel 10 on the kick
+    let mut map = ChannelMap::omni(0);
+    map.channels[9] = Some(1);
+
+    let samples = smf.render(&mut synth, &map, 2.0);
+    wav::write(&output, &samples, SAMPLE_RATE as u32).expect("failed to write wav");
+}
@@ -0,0 +1,167 @@
+use crate::kbd::Note;
+
+/// Order the held notes play in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    #[default]
+    Up,
+    Down,
+    /// Up then down, without repeating the top and bottom notes.
+    UpDown,
+    Random,
+    /// In the order the keys went down.
+    Played,
+}
+
+/// Order by `up`, `down`, `up-down`, `random` or `played`.
+pub fn order_from_name(name: &str) -> Option<Order> {
+    match name {
+        "up" => Some(Order::Up),
+        "down" => Some(Order::Down),
+        "up-down" => Some(Order::UpDown),
+        "random" => Some(Order::Random),
+        "played" => Some(Order::Played),
+        _ => None,
+    }
+}
+
+/// Plays held notes one at a time, timed by a sequencer playhead.
+pub struct Arpeggiator {
+    pub order: Order,
+    /// Octaves the held notes repeat over, 1 plays them as held.
+    pub octaves: u8,
+    /// Sequencer steps per note.
+    pub rate: f64,
+    /// Note length, a fraction of `rate`.
+    pub gate: f64,
+    /// Keep playing released notes until a new chord starts.
+    latch: bool,
+    held: Vec<u8>, // as played
+    pressed: Vec<u8>,
+    /// Position in the note order.
+    index: usize,
+    /// Last note started, counted in `rate`s.
+    tick: Option<i64>,
+    sounding: Option<(u8, f64)>, // note, position to stop at
+}
+
+impl Arpeggiator {
+    pub fn new(order: Order) -> Self {
+        Self {
+            order,
+            octaves: 1,
+            rate: 1.0,
+            gate: 0.5,
+            latch: false,
+            held: Vec::new(),
+            pressed: Vec::new(),
+            index: 0,
+            tick: None,
+            sounding: None,
+        }
+    }
+
+    pub fn latch(&self) -> bool {
+        self.latch
+    }
+
+    /// Turning latch off drops the notes whose keys are up.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.held.retain(|note| self.pressed.contains(note));
+        }
+    }
+
+    /// Notes held, in the order they were played.
+    pub fn held(&self) -> &[u8] {
+        &self.held
+    }
+
+    /// A key going down or up.
+    pub fn input(&mut self, note: Note) {
+        match note {
+            Note::On(note) => {
+                if self.latch && self.pressed.is_empty() {
+                    self.held.clear();
+                }
+                if !self.pressed.contains(&note) {
+                    self.pressed.push(note);
+                }
+                if !self.held.contains(&note) {
+                    self.held.push(note);
+                }
+            }
+            Note::Off(note) => {
+                self.pressed.retain(|&n| n != note);
+                if !self.latch {
+                    self.held.retain(|&n| n != note);
+                }
+            }
+        }
+    }
+
+    /// The held notes across the octaves, in play order.
+    fn sequence(&self) -> Vec<u8> {
+        let mut notes = self.held.clone();
+        if self.order != Order::Played {
+            notes.sort_unstable();
+        }
+        // Past 10 octaves nothing is left in the MIDI range
+        let notes: Vec<u8> = (0..self.octaves.clamp(1, 11))
+            .flat_map(|octave| {
+                notes
+                    .iter()
+                    .filter_map(move |&note| note.checked_add(12 * octave).filter(|&n| n < 128))
+            })
+            .collect();
+
+        match self.order {
+            Order::Down => notes.into_iter().rev().collect(),
+            Order::UpDown if notes.len() > 2 => {
+                let down = notes[1..notes.len() - 1].iter().rev().copied();
+                notes.iter().copied().chain(down).collect()
+            }
+            _ => notes,
+        }
+    }
+
+    /// Follow the sequencer playhead to `position` (in steps), pushing the
+    /// notes to start and stop.
+    pub fn update(&mut self, position: f64, out: &mut Vec<Note>) {
+        if let Some((note, until)) = self.sounding
+            && (position >= until || self.held.is_empty())
+        {
+            out.push(Note::Off(note));
+            self.sounding = None;
+        }
+        if self.held.is_empty() {
+            self.index = 0;
+            self.tick = None;
+            return;
+        }
+
+        let rate = self.rate.max(1e-3);
+        let tick = (position / rate).floor() as i64;
+        if self.tick == Some(tick) {
+            return;
+        }
+        self.tick = Some(tick);
+
+        let sequence = self.sequence();
+        if sequence.is_empty() {
+            return;
+        }
+        let note = match self.order {
+            Order::Random => sequence[rand::random_range(0..sequence.len())],
+            _ => sequence[self.index % sequence.len()],
+        };
+        self.index = (self.index + 1) % sequence.len();
+
+        if let Some((note, _)) = self.sounding.take() {
+            out.push(Note::Off(note));
+        }
+        out.push(Note::On(note));
+        self.sounding = Some((note, (tick as f64 + self.gate) * rate));
+    }
+}
@@ -1,5 +1,6 @@
-#include <ApplicationServices/ApplicationServices.h>
 #include <AudioUnit/AudioUnit.h>
+#include <stdlib.h>
+#include <string.h>
 
 typedef void (*AudioCallback)(void* user_data, float* buffer, uint32_t frame_count);
 
@@ -105,7 +106,3 @@ void audio_engine_free(AudioEngine* engine) {
   AudioComponentInstanceDispose(engine->output_unit);
   free(engine);
 }
-
-bool is_key_down(uint16_t keycode) {
-  return CGEventSourceKeyState(kCGEventSourceStateCombinedSessionState, keycode);
-}
@@ -105,3 +105,320 @@ impl Env {
         self.state == State::Finished
     }
 }
+
+/// Max number of stages a [Stages] envelope can hold.
+pub const MAX_STAGES: usize = 16;
+
+/// A single breakpoint: ramp to `level` over `time` seconds.
+///
+/// `curve` bends the ramp: `0.0` is linear, positive values start slow and
+/// end fast, negative values start fast and end slow.
+#[derive(Default, Clone, Copy)]
+pub struct Stage {
+    pub time: f64,  // secs
+    pub level: f64, // amp
+    pub curve: f64,
+}
+
+/// The Shape of a multi-stage (breakpoint) envelope.
+#[derive(Clone, Copy)]
+pub struct Stages {
+    stages: [Stage; MAX_STAGES],
+    len: usize,
+    /// Stage index whose level is held until `note_off`.
+    pub sustain: Option<usize>,
+    /// Jump back to `.0` after finishing stage `.1` while the note is held.
+    pub looping: Option<(usize, usize)>,
+}
+
+impl Default for Stages {
+    fn default() -> Self {
+        Self::dahdsr(0.0, 0.01, 0.0, 0.02, 0.8, 0.2)
+    }
+}
+
+impl Stages {
+    pub fn new() -> Self {
+        Self {
+            stages: [Stage::default(); MAX_STAGES],
+            len: 0,
+            sustain: None,
+            looping: None,
+        }
+    }
+
+    /// Delay, Attack, Hold, Decay, Sustain, Release.
+    pub fn dahdsr(
+        delay: f64,
+        attack: f64,
+        hold: f64,
+        decay: f64,
+        sustain: f64,
+        release: f64,
+    ) -> Self {
+        Self::new()
+            .stage(delay, 0.0, 0.0)
+            .stage(attack, 1.0, 0.0)
+            .stage(hold, 1.0, 0.0)
+            .stage(decay, sustain, 0.0)
+            .sustain()
+            .stage(release, 0.0, -4.0)
+    }
+
+    pub fn stage(mut self, time: f64, level: f64, curve: f64) -> Self {
+        assert!(self.len < MAX_STAGES, "too many envelope stages");
+        self.stages[self.len] = Stage { time, level, curve };
+        self.len += 1;
+        self
+    }
+
+    /// Hold at the level of the last added stage until `note_off`.
+    pub fn sustain(mut self) -> Self {
+        assert!(self.len > 0);
+        self.sustain = Some(self.len - 1);
+        self
+    }
+
+    /// Loop from stage `start` through the last added stage while held.
+    pub fn loop_from(mut self, start: usize) -> Self {
+        assert!(start < self.len);
+        self.looping = Some((start, self.len - 1));
+        self
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages[..self.len]
+    }
+
+    /// First stage to run after `note_off`.
+    fn release_index(&self) -> usize {
+        match self.sustain {
+            Some(i) => i + 1,
+            None => self.len.saturating_sub(1),
+        }
+    }
+}
+
+/// A runtime multi-stage envelope driven by [Stages].
+#[derive(Default)]
+pub struct Multi {
+    shape: Stages,
+    index: usize,
+    elapsed: f64,
+    start: f64,
+    released: bool,
+    pub amp: f64, // 0..1
+}
+
+impl Multi {
+    pub fn new(shape: Stages) -> Self {
+        Self {
+            shape,
+            index: 0,
+            elapsed: 0.0,
+            start: 0.0,
+            released: false,
+            amp: 0.0,
+        }
+    }
+
+    pub fn note_off(&mut self) {
+        if self.released || self.is_finished() {
+            return;
+        }
+        self.released = true;
+
+        let index = self.shape.release_index();
+        if index > self.index || self.shape.looping.is_some() {
+            self.enter(index);
+        }
+    }
+
+    fn enter(&mut self, index: usize) {
+        self.index = index;
+        self.elapsed = 0.0;
+        self.start = self.amp;
+    }
+
+    pub fn next(&mut self, dt: f64) -> f64 {
+        let Some(&stage) = self.shape.stages().get(self.index) else {
+            return self.amp;
+        };
+
+        if !self.released && self.shape.sustain == Some(self.index) && self.elapsed >= stage.time {
+            return self.amp;
+        }
+
+        self.elapsed += dt;
+
+        let t = if stage.time > 0.0 {
+            (self.elapsed / stage.time).min(1.0)
+        } else {
+            1.0
+        };
+        let t = if stage.curve.abs() < 1e-6 {
+            t
+        } else {
+            (stage.curve * t).exp_m1() / stage.curve.exp_m1()
+        };
+        self.amp = self.start + (stage.level - self.start) * t;
+
+        if self.elapsed >= stage.time {
+            self.amp = stage.level;
+
+            if self.shape.sustain == Some(self.index) && !self.released {
+                return self.amp;
+            }
+
+            match self.shape.looping {
+                Some((start, end)) if !self.released && self.index == end => self.enter(start),
+                _ => self.enter(self.index + 1),
+            }
+        }
+
+        self.amp
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.index >= self.shape.len
+    }
+}
+
+/// Which envelope an instrument uses.
+// Stages are kept inline so voices never allocate on the audio thread.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Adsr(Shape),
+    Stages(Stages),
+}
+
+impl Default for Kind {
+    fn default() -> Self {
+        Self::Adsr(Shape::default())
+    }
+}
+
+impl Kind {
+    /// Scale the times leading up to the sustain, release is untouched.
+    pub fn scale_time(self, factor: f64) -> Self {
+        match self {
+            Self::Adsr(mut shape) => {
+                shape.attack *= factor;
+                shape.decay *= factor;
+                Self::Adsr(shape)
+            }
+            Self::Stages(mut stages) => {
+                let end = stages.release_index();
+                for stage in &mut stages.stages[..end] {
+                    stage.time *= factor;
+                }
+                Self::Stages(stages)
+            }
+        }
+    }
+}
+
+/// A runtime envelope, either an [Env] or a [Multi].
+#[allow(clippy::large_enum_variant)]
+pub enum Envelope {
+    Adsr(Env),
+    Multi(Multi),
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::Adsr(Env::default())
+    }
+}
+
+impl Envelope {
+    pub fn new(kind: Kind) -> Self {
+        match kind {
+            Kind::Adsr(shape) => Self::Adsr(Env::new(shape)),
+            Kind::Stages(stages) => Self::Multi(Multi::new(stages)),
+        }
+    }
+
+    /// Restart from the first stage, ramping from the current level instead of 0.
+    /// While sounding, leading stages at level 0 like the delay of
+    /// [Stages::dahdsr] are skipped so the level doesn't drop before the attack.
+    pub fn retrigger(&mut self, kind: Kind) {
+        let amp = self.amp();
+        *self = match kind {
+            Kind::Adsr(shape) => Self::Adsr(Env {
+                amp,
+                ..Env::new(shape)
+            }),
+            Kind::Stages(stages) => {
+                let mut env = Multi {
+                    amp,
+                    ..Multi::new(stages)
+                };
+                let silent = match amp > 0.0 {
+                    true => stages
+                        .stages()
+                        .iter()
+                        .take_while(|s| s.level <= 0.0)
+                        .count(),
+                    false => 0,
+                };
+                env.enter(silent.min(stages.release_index()));
+                Self::Multi(env)
+            }
+        };
+    }
+
+    pub fn note_off(&mut self) {
+        match self {
+            Self::Adsr(env) => env.note_off(),
+            Self::Multi(env) => env.note_off(),
+        }
+    }
+
+    pub fn next(&mut self, dt: f64) -> f64 {
+        match self {
+            Self::Adsr(env) => env.next(dt),
+            Self::Multi(env) => env.next(dt),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Self::Adsr(env) => env.is_finished(),
+            Self::Multi(env) => env.is_finished(),
+        }
+    }
+
+    pub fn amp(&self) -> f64 {
+        match self {
+            Self::Adsr(env) => env.amp,
+            Self::Multi(env) => env.amp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retrigger_skips_delay() {
+        let dt = 1.0 / 44100.0;
+        let stages = Stages::dahdsr(0.05, 0.01, 0.0, 0.1, 0.8, 0.1);
+        let mut env = Envelope::new(Kind::Stages(stages));
+        for _ in 0..44100 {
+            env.next(dt);
+        }
+        assert!((env.amp() - 0.8).abs() < 1e-9);
+
+        env.retrigger(Kind::Stages(stages));
+        let mut last = env.amp();
+        for _ in 0..441 {
+            let amp = env.next(dt);
+            assert!(amp >= last);
+            last = amp;
+        }
+        assert!((last - 1.0).abs() < 1e-9);
+    }
+}
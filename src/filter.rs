@@ -0,0 +1,41 @@
+use crate::consts::PI;
+
+/// Resonant low-pass, a TPT state variable filter.
+#[derive(Default)]
+pub struct Filter {
+    sr: f64,
+    resonance: f64, // 0..1
+    ic1: f64,
+    ic2: f64,
+}
+
+impl Filter {
+    pub fn new(sr: f64, resonance: f64) -> Self {
+        Self {
+            sr,
+            resonance: resonance.clamp(0.0, 0.98),
+            ic1: 0.0,
+            ic2: 0.0,
+        }
+    }
+
+    /// Low-pass `input` at `cutoff` Hz.
+    pub fn next(&mut self, input: f64, cutoff: f64) -> f64 {
+        let cutoff = cutoff.clamp(20.0, self.sr * 0.49);
+        let g = (PI * cutoff / self.sr).tan();
+        let k = 2.0 - 2.0 * self.resonance;
+
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2;
+        let v1 = a1 * self.ic1 + a2 * v3;
+        let v2 = self.ic2 + a2 * self.ic1 + a3 * v3;
+
+        self.ic1 = 2.0 * v1 - self.ic1;
+        self.ic2 = 2.0 * v2 - self.ic2;
+
+        v2
+    }
+}
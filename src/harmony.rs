@@ -0,0 +1,242 @@
+use std::io;
+
+use crate::{Event, invalid};
+
+/// Pitch class of a note name like `C`, `F#` or `Bb`.
+pub fn pitch_class(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let base: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let accidental = match chars.as_str() {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return None,
+    };
+    Some((base + accidental).rem_euclid(12) as u8)
+}
+
+/// Semitones of a chord above its root by name, `maj`, `min`, `dim`, `aug`,
+/// `sus2`, `sus4`, `7`, `maj7`, `min7` or `5`.
+pub fn chord_from_name(name: &str) -> Option<Vec<u8>> {
+    let chord: &[u8] = match name {
+        "maj" => &[0, 4, 7],
+        "min" => &[0, 3, 7],
+        "dim" => &[0, 3, 6],
+        "aug" => &[0, 4, 8],
+        "sus2" => &[0, 2, 7],
+        "sus4" => &[0, 5, 7],
+        "7" => &[0, 4, 7, 10],
+        "maj7" => &[0, 4, 7, 11],
+        "min7" => &[0, 3, 7, 10],
+        "5" => &[0, 7],
+        _ => return None,
+    };
+    Some(chord.to_vec())
+}
+
+/// A key to play in, the pitch classes of a mode above a root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scale {
+    /// Pitch class, 0 for C.
+    pub root: u8,
+    /// Semitones above the root within an octave, ascending from 0.
+    pub steps: Vec<u8>,
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self::new(0, vec![0, 2, 4, 5, 7, 9, 11])
+    }
+}
+
+impl Scale {
+    pub fn new(root: u8, mut steps: Vec<u8>) -> Self {
+        steps.retain(|&step| step < 12);
+        steps.push(0);
+        steps.sort_unstable();
+        steps.dedup();
+        Self {
+            root: root % 12,
+            steps,
+        }
+    }
+
+    /// A root and a mode like `D minor` or `F# pentatonic`. Modes are
+    /// `major`, `minor`, `dorian`, `phrygian`, `lydian`, `mixolydian`,
+    /// `locrian`, `harmonic-minor`, `pentatonic`, `minor-pentatonic`,
+    /// `blues` and `chromatic`.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut words = text.split_whitespace();
+        let (Some(root), Some(mode), None) = (words.next(), words.next(), words.next()) else {
+            return Err(invalid("expected a root and a mode"));
+        };
+        let root = pitch_class(root).ok_or_else(|| invalid("invalid root note"))?;
+        let steps: &[u8] = match mode {
+            "major" | "ionian" => &[0, 2, 4, 5, 7, 9, 11],
+            "minor" | "aeolian" => &[0, 2, 3, 5, 7, 8, 10],
+            "dorian" => &[0, 2, 3, 5, 7, 9, 10],
+            "phrygian" => &[0, 1, 3, 5, 7, 8, 10],
+            "lydian" => &[0, 2, 4, 6, 7, 9, 11],
+            "mixolydian" => &[0, 2, 4, 5, 7, 9, 10],
+            "locrian" => &[0, 1, 3, 5, 6, 8, 10],
+            "harmonic-minor" => &[0, 2, 3, 5, 7, 8, 11],
+            "pentatonic" => &[0, 2, 4, 7, 9],
+            "minor-pentatonic" => &[0, 3, 5, 7, 10],
+            "blues" => &[0, 3, 5, 6, 7, 10],
+            "chromatic" => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            _ => return Err(invalid("unknown mode")),
+        };
+        Ok(Self::new(root, steps.to_vec()))
+    }
+
+    pub fn contains(&self, note: u8) -> bool {
+        let step = (note as i32 - self.root as i32).rem_euclid(12) as u8;
+        self.steps.contains(&step)
+    }
+
+    /// The nearest note in the scale, the lower one on a tie.
+    pub fn quantize(&self, note: u8) -> u8 {
+        (0..12u8)
+            .flat_map(|d| {
+                [
+                    note.checked_sub(d),
+                    note.checked_add(d).filter(|&n| n < 128),
+                ]
+            })
+            .flatten()
+            .find(|&n| self.contains(n))
+            .unwrap_or(note)
+    }
+
+    /// `note` moved by `degrees` steps of the scale, keeping how far it is
+    /// off the scale. `None` outside the MIDI range.
+    pub fn transpose(&self, note: u8, degrees: i32) -> Option<u8> {
+        let size = self.steps.len() as i32;
+        let above_root = note as i32 - self.root as i32;
+        let step = above_root.rem_euclid(12);
+        // Highest scale step at or below the note, the first is always 0
+        let index = self.steps.iter().rposition(|&s| s as i32 <= step)? as i32;
+        let off = step - self.steps[index as usize] as i32;
+
+        let degree = above_root.div_euclid(12) * size + index + degrees;
+        let note = self.root as i32
+            + degree.div_euclid(size) * 12
+            + self.steps[degree.rem_euclid(size) as usize] as i32
+            + off;
+        u8::try_from(note).ok().filter(|&n| n < 128)
+    }
+}
+
+/// Turns each played note into the notes to sound: snapped to a scale, as a
+/// remembered chord and with diatonic harmonies. Other events pass through.
+#[derive(Debug, Clone, Default)]
+pub struct Harmonizer {
+    pub scale: Scale,
+    /// Snap played notes into `scale`.
+    pub quantize: bool,
+    /// Chord memory, semitones above the played note, empty plays it alone.
+    pub chord: Vec<u8>,
+    /// Scale degrees above the played note to add, like `[2, 4]` for triads.
+    pub harmony: Vec<i32>,
+    sounding: Vec<(usize, u8, Vec<u8>)>, // inst, played, started
+    /// Played notes each started note sounds for.
+    users: Vec<(usize, u8, usize)>, // inst, started, count
+}
+
+impl Harmonizer {
+    /// Remember `notes` as the chord, relative to the lowest.
+    pub fn memorize(&mut self, notes: &[u8]) {
+        let Some(&low) = notes.iter().min() else {
+            self.chord.clear();
+            return;
+        };
+        self.chord = notes.iter().map(|&note| note - low).collect();
+        self.chord.sort_unstable();
+        self.chord.dedup();
+    }
+
+    /// Notes `note` sounds as.
+    fn notes(&self, note: u8) -> Vec<u8> {
+        let note = match self.quantize {
+            true => self.scale.quantize(note),
+            false => note,
+        };
+        let chord = match self.chord.is_empty() {
+            true => &[0][..],
+            false => &self.chord,
+        };
+
+        let mut notes: Vec<u8> = chord
+            .iter()
+            .filter_map(|&interval| note.checked_add(interval).filter(|&n| n < 128))
+            .chain(
+                self.harmony
+                    .iter()
+                    .filter_map(|&degrees| self.scale.transpose(note, degrees)),
+            )
+            .collect();
+        notes.sort_unstable();
+        notes.dedup();
+        notes
+    }
+
+    /// Push the events `event` turns into. A note off stops what its note
+    /// on started, even if the settings changed since. Notes started by
+    /// several played notes start once and stop with the last of them.
+    pub fn process(&mut self, event: Event, out: &mut Vec<Event>) {
+        match event {
+            Event::NoteOn(inst, note, velocity) => {
+                self.release(inst, note, out);
+                let notes = self.notes(note);
+                for &n in &notes {
+                    match self.users.iter_mut().find(|u| u.0 == inst && u.1 == n) {
+                        Some(user) => user.2 += 1,
+                        None => {
+                            self.users.push((inst, n, 1));
+                            out.push(Event::NoteOn(inst, n, velocity));
+                        }
+                    }
+                }
+                self.sounding.push((inst, note, notes));
+            }
+            Event::NoteOff(inst, note) => {
+                if !self.release(inst, note, out) {
+                    out.push(event);
+                }
+            }
+            event => out.push(event),
+        }
+    }
+
+    /// Stop what `note` started, `false` if it isn't sounding.
+    fn release(&mut self, inst: usize, note: u8, out: &mut Vec<Event>) -> bool {
+        let Some(i) = self
+            .sounding
+            .iter()
+            .position(|&(i, n, _)| i == inst && n == note)
+        else {
+            return false;
+        };
+        let (_, _, notes) = self.sounding.swap_remove(i);
+        for n in notes {
+            let Some(u) = self.users.iter().position(|u| u.0 == inst && u.1 == n) else {
+                continue;
+            };
+            self.users[u].2 -= 1;
+            if self.users[u].2 == 0 {
+                self.users.swap_remove(u);
+                out.push(Event::NoteOff(inst, n));
+            }
+        }
+        true
+    }
+}
@@ -1,178 +1,256 @@
-use crate::Hz;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-#[repr(u16)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[allow(unused)]
-pub enum KeyCode {
-    Q = 12,
-    W = 13,
-    E = 14,
-    R = 15,
-    T = 17,
-    Y = 16,
-    U = 32,
-    I = 34,
-    O = 31,
-    P = 35,
-    LBracket = 33,
-    RBracket = 30,
-    BSlash = 42,
-
-    A = 0,
-    S = 1,
-    D = 2,
-    F = 3,
-    G = 5,
-    H = 4,
-    J = 38,
-    K = 40,
-    L = 37,
-    Semi = 41,
-    Quote = 39,
-    Enter = 36,
-    Home = 115,
-
-    Z = 6,
-    X = 7,
-    C = 8,
-    V = 9,
-    B = 11,
-    N = 45,
-    M = 46,
-    Comma = 43,
-    Dot = 47,
-    FSLash = 44,
+use ratatui::crossterm::event::{
+    KeyCode, KeyEvent, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+    PushKeyboardEnhancementFlags,
+};
+use ratatui::crossterm::{execute, terminal};
+
+/// Key by the character it types, or `enter`, `home`, `space`, `tab`,
+/// `semi`, `quote` and `slash`.
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    match name {
+        "enter" => Some(KeyCode::Enter),
+        "home" => Some(KeyCode::Home),
+        "space" => Some(KeyCode::Char(' ')),
+        "tab" => Some(KeyCode::Tab),
+        "semi" => Some(KeyCode::Char(';')),
+        "quote" => Some(KeyCode::Char('\'')),
+        "slash" => Some(KeyCode::Char('/')),
+        _ => {
+            let mut chars = name.chars();
+            let c = chars.next()?;
+            chars
+                .next()
+                .is_none()
+                .then(|| KeyCode::Char(c.to_ascii_lowercase()))
+        }
+    }
 }
 
-#[inline]
-pub fn is_key_down(key: KeyCode) -> bool {
-    unsafe extern "C" {
-        #[link_name = "is_key_down"]
-        fn c_is_key_down(keycode: u16) -> bool;
+/// Ask the terminal to report key repeats and releases (the kitty keyboard
+/// protocol), `false` if it can't and releases have to be guessed.
+pub fn enable_releases() -> bool {
+    if !terminal::supports_keyboard_enhancement().unwrap_or(false) {
+        return false;
     }
-    unsafe { c_is_key_down(key as u16) }
+    execute!(
+        io::stdout(),
+        PushKeyboardEnhancementFlags(
+            KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES
+        )
+    )
+    .is_ok()
+}
+
+/// Undo [enable_releases].
+pub fn disable_releases() {
+    _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+}
+
+/// Note changes from the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Note {
+    On(u8),
+    Off(u8),
+}
+
+/// What pressing a key does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Play the note this many semitones above the keyboard's lowest note.
+    Note(u8),
+    OctaveDown,
+    OctaveUp,
+    TransposeDown,
+    TransposeUp,
 }
 
 #[derive(Clone, Copy)]
 pub struct Key {
     pub code: KeyCode,
-    pub midi: u8,
+    pub action: Action,
     pub pressed: bool,
+    /// Note sounding while pressed, released even if the octave changed since.
+    note: Option<u8>,
+    /// Last press or repeat, for guessed releases.
+    seen: Option<Instant>,
 }
 
+/// Two rows of a piano on US QWERTY, Z/X shift octaves and C/V transpose.
+/// Other layouts list the characters on the same keys instead.
+pub const QWERTY: &str = "
+a 0
+w 1
+s 2
+e 3
+d 4
+f 5
+t 6
+g 7
+y 8
+h 9
+u 10
+j 11
+k 12
+o 13
+l 14
+p 15
+; 16
+' 17
+z octave-down
+x octave-up
+c transpose-down
+v transpose-up
+";
+
+/// The computer keyboard as a note controller.
 pub struct Keyboard {
-    pub keys: [Key; 18],
+    pub keys: Vec<Key>,
+    /// Note of `Action::Note(0)` before shifting.
+    pub base: u8,
+    pub octave: i8,
+    /// Semitones.
+    pub transpose: i8,
+    /// Without release events, release keys when they haven't repeated for
+    /// this long. Has to outlast the terminal's key repeat delay.
+    pub release_after: Option<Duration>,
 }
 
 impl Keyboard {
     pub fn new() -> Self {
-        Self {
-            keys: [
-                Key {
-                    code: KeyCode::A,
-                    midi: 0,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::W,
-                    midi: 1,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::S,
-                    midi: 2,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::E,
-                    midi: 3,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::D,
-                    midi: 4,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::F,
-                    midi: 5,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::T,
-                    midi: 6,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::G,
-                    midi: 7,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::Y,
-                    midi: 8,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::H,
-                    midi: 9,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::U,
-                    midi: 10,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::J,
-                    midi: 11,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::K,
-                    midi: 12,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::O,
-                    midi: 13,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::L,
-                    midi: 14,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::P,
-                    midi: 15,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::Semi,
-                    midi: 16,
-                    pressed: false,
-                },
-                Key {
-                    code: KeyCode::Quote,
-                    midi: 17,
-                    pressed: false,
-                },
-            ],
+        Self::parse(QWERTY).expect("valid default layout")
+    }
+
+    /// Parse a layout, a `<key> <action>` pair per line where the key is a
+    /// [key_from_name] and the action a semitone offset or one of
+    /// `octave-down`, `octave-up`, `transpose-down` and `transpose-up`.
+    /// `#` starts a comment.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid layout line: {line}"),
+            )
+        };
+
+        let mut keys = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let (Some(name), Some(action), None) = (words.next(), words.next(), words.next())
+            else {
+                return Err(invalid(line));
+            };
+            let code = key_from_name(name).ok_or_else(|| invalid(line))?;
+            let action = match action {
+                "octave-down" => Action::OctaveDown,
+                "octave-up" => Action::OctaveUp,
+                "transpose-down" => Action::TransposeDown,
+                "transpose-up" => Action::TransposeUp,
+                offset => Action::Note(offset.parse().map_err(|_| invalid(line))?),
+            };
+
+            keys.push(Key {
+                code,
+                action,
+                pressed: false,
+                note: None,
+                seen: None,
+            });
         }
+
+        Ok(Self {
+            keys,
+            base: 60,
+            octave: 0,
+            transpose: 0,
+            release_after: None,
+        })
     }
 
-    #[allow(unused)]
-    fn press(&mut self, _keycode: KeyCode) {
-        // if let Some(index) = self.map[keycode as usize] {
-        //     self.keys[index].pressed = true;
-        // }
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// MIDI note for a semitone `offset` at the current octave and transpose.
+    pub fn note(&self, offset: u8) -> Option<u8> {
+        let note = self.base as i32 + self.octave as i32 * 12 + self.transpose as i32;
+        u8::try_from(note + offset as i32).ok().filter(|&n| n < 128)
+    }
+
+    /// Notes of the keys held down.
+    pub fn held(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keys
+            .iter()
+            .filter(|k| k.pressed)
+            .filter_map(|k| k.note)
+    }
+
+    /// Handle a terminal key event, repeats only keep keys held.
+    pub fn key_event(&mut self, event: KeyEvent, now: Instant) -> Option<Note> {
+        let code = match event.code {
+            KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+            code => code,
+        };
+        match event.kind {
+            KeyEventKind::Press | KeyEventKind::Repeat => self.press(code, now).map(Note::On),
+            KeyEventKind::Release => self.release(code).map(Note::Off),
+        }
     }
 
-    #[allow(unused)]
-    fn release(&mut self, _keycode: KeyCode) {
-        // if let Some(index) = self.map[keycode as usize] {
-        //     self.keys[index].pressed = true;
-        // }
+    /// Press `keycode`, returns the note to start, if any.
+    pub fn press(&mut self, keycode: KeyCode, now: Instant) -> Option<u8> {
+        let index = self.keys.iter().position(|k| k.code == keycode)?;
+        self.keys[index].seen = Some(now);
+        if self.keys[index].pressed {
+            return None;
+        }
+        self.keys[index].pressed = true;
+
+        match self.keys[index].action {
+            Action::Note(offset) => {
+                let note = self.note(offset);
+                self.keys[index].note = note;
+                return note;
+            }
+            Action::OctaveDown => self.octave = (self.octave - 1).max(-5),
+            Action::OctaveUp => self.octave = (self.octave + 1).min(5),
+            Action::TransposeDown => self.transpose = (self.transpose - 1).max(-12),
+            Action::TransposeUp => self.transpose = (self.transpose + 1).min(12),
+        }
+        None
+    }
+
+    /// Release `keycode`, returns the note to stop, if any.
+    pub fn release(&mut self, keycode: KeyCode) -> Option<u8> {
+        let key = self.keys.iter_mut().find(|k| k.code == keycode)?;
+        key.pressed = false;
+        key.seen = None;
+        key.note.take()
+    }
+
+    /// Release a key not seen for [Keyboard::release_after], returns its note.
+    /// Call until `None`.
+    pub fn expire(&mut self, now: Instant) -> Option<u8> {
+        let after = self.release_after?;
+        for key in &mut self.keys {
+            if key.pressed && key.seen.is_some_and(|seen| now - seen >= after) {
+                key.pressed = false;
+                key.seen = None;
+                if let Some(note) = key.note.take() {
+                    return Some(note);
+                }
+            }
+        }
+        None
     }
 }
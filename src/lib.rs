@@ -1,15 +1,39 @@
 mod engine;
 pub use engine::Engine;
 
+mod synth;
+pub use synth::{DEFAULT_VELOCITY, Event, Meter, Policy, Status, Steal, Synth};
+
+pub mod arp;
 pub mod env;
+pub mod filter;
+pub mod harmony;
 pub mod kbd;
+pub mod limiter;
+pub mod midi;
+pub mod modulation;
 pub mod osc;
 pub mod preset;
+pub mod rhythm;
+pub mod scope;
+pub mod seq;
+pub mod smf;
+pub mod tui;
+pub mod tuning;
+pub mod wav;
 
 pub mod consts {
     pub use std::f64::consts::{PI, TAU};
 }
 
+/// Error for malformed input to the file and text parsers.
+pub(crate) fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Standard concert pitch, A4 (MIDI note 69) in Hz.
+pub const A4: f64 = 440.0;
+
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Hz(pub f64);
 
@@ -26,6 +50,17 @@ impl Hz {
 
         Hz(PITCH_STANDARD * TWELFTH_ROOT_OF_TWO.powi(semitones))
     }
+
+    /// Equal tempered frequency of a MIDI `note`, fractional for bends, with
+    /// A4 tuned to `a4` Hz.
+    pub fn from_midi(note: f64, a4: f64) -> Self {
+        Hz(a4 * ((note - 69.0) / 12.0).exp2())
+    }
+
+    /// Fractional MIDI note of this frequency with A4 tuned to `a4` Hz.
+    pub fn to_midi(&self, a4: f64) -> f64 {
+        69.0 + 12.0 * (self.0 / a4).log2()
+    }
 }
 
 impl From<f64> for Hz {
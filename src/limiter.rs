@@ -0,0 +1,63 @@
+/// Look-ahead brickwall limiter, the output never goes over `ceiling`.
+///
+/// The gain needed by each sample is held for the look-ahead window and then
+/// averaged over it, so the gain has ramped down by the time a peak leaves
+/// the delay line.
+pub struct Limiter {
+    pub ceiling: f64,
+    delay: Vec<f64>,  // input
+    needed: Vec<f64>, // gain each sample in the window needs
+    held: Vec<f64>,   // window minimum of `needed`, for the average
+    sum: f64,         // of `held`
+    pos: usize,
+    release: f64, // coef
+    gain: f64,
+}
+
+impl Limiter {
+    /// `lookahead` and `release` in secs, `ceiling` as a linear level.
+    pub fn new(sr: f64, lookahead: f64, release: f64, ceiling: f64) -> Self {
+        let len = ((lookahead * sr) as usize).max(1);
+        Self {
+            ceiling,
+            delay: vec![0.0; len],
+            needed: vec![1.0; len],
+            held: vec![1.0; len],
+            sum: len as f64,
+            pos: 0,
+            release: 1.0 - (-1.0 / (release * sr)).exp(),
+            gain: 1.0,
+        }
+    }
+
+    /// Current gain, 1.0 when not limiting.
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    pub fn next(&mut self, input: f64) -> f64 {
+        let len = self.delay.len();
+        self.delay[self.pos] = input;
+        // Oldest sample, it was in every window averaged into the gain below
+        let output = self.delay[(self.pos + 1) % len];
+
+        self.needed[self.pos] = (self.ceiling / input.abs()).min(1.0);
+        let held = self.needed.iter().copied().fold(1.0, f64::min);
+        self.sum += held - std::mem::replace(&mut self.held[self.pos], held);
+        self.pos = (self.pos + 1) % len;
+
+        // Back to a clean sum once per window, against float drift
+        if self.pos == 0 {
+            self.sum = self.held.iter().sum();
+        }
+
+        let target = self.sum / len as f64;
+        if target < self.gain {
+            self.gain = target;
+        } else {
+            self.gain += (target - self.gain) * self.release;
+        }
+
+        (output * self.gain).clamp(-self.ceiling, self.ceiling)
+    }
+}
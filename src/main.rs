@@ -1,310 +1,318 @@
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
 use std::time::{Duration, Instant};
-use std::{cmp, thread};
 
-use synth::env::Env;
-use synth::kbd::{self, KeyCode, Keyboard};
-use synth::osc::{Osc, Waveform};
+use ratatui::crossterm::event::{self as term, KeyCode, KeyEventKind, KeyModifiers};
+use synth::arp::{self, Arpeggiator};
+use synth::harmony::{self, Harmonizer, Scale};
+use synth::kbd::{self, Keyboard, Note};
+use synth::midi;
+use synth::modulation::{Source, Target};
+use synth::osc::Waveform;
 use synth::preset::{self, Instrument};
-use synth::{Engine, Hz};
-
-#[derive(Default)]
-struct Voice {
-    inst_id: usize,
-    /// Wether this voice is _currently_ producing sound
-    active: bool,
-    /// Midi note 0..128
-    note: u8,
-    freq: Hz,
-    env: Env,
-    lfos: Vec<Osc>,
-    oscs: Vec<Osc>,
-}
+use synth::seq::{ClockSource, Sequencer, Track};
+use synth::smf::{self, Smf};
+use synth::tui;
+use synth::tuning::{Mapping, Tuning};
+use synth::{DEFAULT_VELOCITY, Engine, Event, Synth};
 
-enum Event {
-    NoteOn(usize, u8),
-    NoteOff(usize, u8),
-    Trigger(usize),
-}
+const SAMPLE_RATE: f64 = 44_100.0;
 
-thread_local! {
-    static FREQ_MAP: [Hz; 18] = [
-        Hz::from_pitch_std(-9),
-        Hz::from_pitch_std(-8),
-        Hz::from_pitch_std(-7),
-        Hz::from_pitch_std(-6),
-        Hz::from_pitch_std(-5),
-        Hz::from_pitch_std(-4),
-        Hz::from_pitch_std(-3),
-        Hz::from_pitch_std(-2),
-        Hz::from_pitch_std(-1),
-        Hz::from_pitch_std(0),
-        Hz::from_pitch_std(1),
-        Hz::from_pitch_std(2),
-        Hz::from_pitch_std(3),
-        Hz::from_pitch_std(4),
-        Hz::from_pitch_std(5),
-        Hz::from_pitch_std(6),
-        Hz::from_pitch_std(7),
-        Hz::from_pitch_std(8),
-    ]
+/// Value following a `--name` flag on the command line.
+fn arg(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
 }
 
-struct Synth<const N: usize = 64> {
-    voices: [Voice; N],
-    instruments: Vec<Instrument>,
-    rx: mpsc::Receiver<Event>,
-}
+fn main() {
+    let (tx, rx) = mpsc::channel();
 
-impl<const N: usize> Synth<N> {
-    fn new(rx: mpsc::Receiver<Event>, instruments: Vec<Instrument>) -> Self {
-        Self {
-            voices: std::array::from_fn(|_| Voice {
-                oscs: Vec::with_capacity(5),
-                lfos: Vec::with_capacity(5),
-                ..Default::default()
-            }),
-            instruments,
-            rx,
-        }
-    }
+    let instrument = Instrument::builder()
+        .lfo(Waveform::Sine, 3.0, 0.02)
+        .osc(Waveform::Sine, 1.0)
+        .osc(Waveform::Saw, 0.2)
+        .env(0.002, 0.1, 0.8, 0.2)
+        .route(Source::ModWheel, Target::LfoDepth, 4.0)
+        .route(Source::Aftertouch, Target::Amp, 0.5)
+        .reserve(8)
+        .build();
 
-    fn find_voice_slot(&self) -> usize {
-        // Try to find a free voice first
-        if let Some(idx) = self.voices.iter().position(|v| !v.active) {
-            return idx;
-        }
+    let instruments = vec![instrument, preset::kick(), preset::snare(), preset::hihat()];
 
-        // If none free, steal the one with lowest amplitude
-        self.voices
-            .iter()
-            .enumerate()
-            .min_by(|(_, a), (_, b)| {
-                a.env
-                    .amp
-                    .partial_cmp(&b.env.amp)
-                    .unwrap_or(cmp::Ordering::Equal)
-            })
-            .unwrap()
-            .0
+    let instrument_count = instruments.len();
+    let mut synth = Synth::<32>::new(SAMPLE_RATE, rx, instruments);
+    // e.g. `--a4 432`
+    if let Some(a4) = arg("--a4") {
+        synth.a4 = a4
+            .parse()
+            .unwrap_or_else(|e| panic!("invalid --a4 {a4}: {e}"));
     }
 
-    fn init_voice(&mut self, inst: usize, note: Option<u8>) {
-        let index = self.find_voice_slot();
-        let voice = &mut self.voices[index];
-
-        voice.inst_id = inst;
-        voice.active = true;
-
-        let instrument = &self.instruments[inst];
-
-        match instrument.kind {
-            preset::Kind::Pitched => {
-                voice.note = note.unwrap();
-                voice.freq = FREQ_MAP.with(|m| m[voice.note as usize]);
-            }
-            preset::Kind::Percussive(freq) => {
-                voice.freq = freq;
-            }
-        };
-
-        voice.env = Env::new(instrument.shape);
+    // Lead tuning, e.g. `--edo 19` or `--scl just.scl [--kbm just.kbm]`
+    let mut tuning = arg("--edo").map(|n| {
+        Tuning::edo(
+            n.parse()
+                .unwrap_or_else(|e| panic!("invalid --edo {n}: {e}")),
+        )
+    });
+    if let Some(path) = arg("--scl") {
+        tuning = Some(Tuning::load(&path).unwrap_or_else(|e| panic!("failed to load {path}: {e}")));
+    }
+    if let Some(path) = arg("--kbm") {
+        let mapping = Mapping::load(&path).unwrap_or_else(|e| panic!("failed to load {path}: {e}"));
+        tuning = Some(tuning.unwrap_or_default().with_mapping(mapping));
+    }
+    if tuning.is_some() {
+        synth.set_tuning(0, tuning);
+    }
+    let mut app = tui::App::new(0, synth.instrument(0), synth.status(), tx.clone());
+    let engine = Engine::new(SAMPLE_RATE, move |buf| synth.process(buf));
 
-        voice.lfos.clear();
-        for &(waveform, freq, gain) in &instrument.lfos {
-            voice
-                .lfos
-                .push(Osc::new(waveform, freq.into(), SAMPLE_RATE, gain));
-        }
+    engine.start();
 
-        voice.oscs.clear();
-        for &(waveform, gain) in &instrument.oscs {
-            voice
-                .oscs
-                .push(Osc::new(waveform, voice.freq, SAMPLE_RATE, gain));
-        }
+    // Play in key with `--scale "D minor"`, a chord per key with `--chord min7`
+    // or `--chord 0,3,7`, and diatonic thirds and fifths with `--harmonize 2,4`
+    let mut harmonizer = Harmonizer::default();
+    if let Some(scale) = arg("--scale") {
+        harmonizer.scale =
+            Scale::parse(&scale).unwrap_or_else(|e| panic!("invalid --scale {scale}: {e}"));
+        harmonizer.quantize = true;
     }
-
-    fn note_on(&mut self, inst: usize, note: u8) {
-        self.init_voice(inst, Some(note));
+    if let Some(chord) = arg("--chord") {
+        harmonizer.chord = harmony::chord_from_name(&chord)
+            .or_else(|| chord.split(',').map(|n| n.parse().ok()).collect())
+            .unwrap_or_else(|| panic!("invalid --chord {chord}"));
     }
-
-    fn note_off(&mut self, inst: usize, note: u8) {
-        for v in self
-            .voices
-            .iter_mut()
-            .filter(|v| v.active && v.inst_id == inst && v.note == note)
-        {
-            v.env.note_off();
-        }
+    if let Some(degrees) = arg("--harmonize") {
+        harmonizer.harmony = degrees
+            .split(',')
+            .map(|d| {
+                d.parse()
+                    .unwrap_or_else(|e| panic!("invalid --harmonize {degrees}: {e}"))
+            })
+            .collect();
     }
 
-    fn trigger(&mut self, inst: usize) {
-        self.init_voice(inst, None);
-    }
+    // Shared by the keyboard and MIDI input, so both play the memorized chord
+    // and a note held on both sounds until both release it
+    let harmonizer = Arc::new(Mutex::new(harmonizer));
 
-    fn process(&mut self, buf: &mut [f32]) {
-        let dt = 1.0 / SAMPLE_RATE;
+    // MIDI clock and transport from `--midi` when following with `--clock-in`
+    let (clock_tx, clock_rx) = mpsc::channel();
+    let clock_in = std::env::args().any(|arg| arg == "--clock-in");
 
-        for _ in 0..128 {
-            let Ok(event) = self.rx.try_recv() else {
-                break;
-            };
-            match event {
-                Event::NoteOn(inst, note) => self.note_on(inst, note),
-                Event::NoteOff(inst, note) => self.note_off(inst, note),
-                Event::Trigger(inst) => self.trigger(inst),
-            }
+    // e.g. `--midi /dev/snd/midiC1D0`, a named pipe, or `-` for stdin
+    if let Some(path) = arg("--midi") {
+        let mut map = midi::ChannelMap::omni(0);
+        // MPE controller with all 15 member channels in the lower zone
+        if std::env::args().any(|arg| arg == "--mpe") {
+            map = map.zone(midi::Zone::lower(15, 0));
         }
 
-        for sample in buf {
-            let mut mix = 0.0;
-
-            for voice in self.voices.iter_mut().filter(|v| v.active) {
-                let amp = voice.env.next(dt);
-
-                if voice.env.is_finished() {
-                    voice.active = false;
-                    continue;
+        let harmonizer = harmonizer.clone();
+        let mut events = Vec::new();
+        let tx = tx.clone();
+        let handler = move |msg: midi::Message| match msg.channel() {
+            Some(_) => {
+                if let Some(event) = map.event(msg) {
+                    harmonizer.lock().unwrap().process(event, &mut events);
                 }
-
-                let lfo = voice.lfos.iter_mut().map(|lfo| lfo.next()).sum::<f64>();
-
-                let sum = voice
-                    .oscs
-                    .iter_mut()
-                    .map(|osc| {
-                        osc.mod_freq(lfo);
-                        osc.next()
-                    })
-                    .sum::<f64>();
-
-                // mix += amp * (sum / voice.oscs.len() as f64);
-                mix += amp * sum;
+                events.drain(..).all(|event| tx.send(event).is_ok())
             }
+            None => !clock_in || clock_tx.send(msg).is_ok(),
+        };
 
-            // master gain
-            *sample = (0.2 * mix) as f32;
-        }
+        midi::open(&path, handler)
+            .unwrap_or_else(|e| panic!("failed to open midi input {path}: {e}"));
     }
-}
-
-#[allow(unused)]
-struct Sequencer {
-    bpm: f64,
-    beats: u8,
-    sub_beats: u8,
-    beat_duration: Duration,
-    total_beats: usize,
-    current_beat: usize,
-    last_time: Instant,
-    // channels: Vec<(usize, Vec<u8>)>,
-    channels: Vec<(usize, u16)>,
-    tx: mpsc::Sender<Event>,
-}
 
-impl Sequencer {
-    fn new(bpm: f64, beats: u8, sub_beats: u8, tx: mpsc::Sender<Event>) -> Self {
-        Self {
-            bpm,
-            beats,
-            sub_beats,
-            beat_duration: Duration::from_secs_f64(60.0 / bpm / sub_beats as f64),
-            current_beat: 0,
-            last_time: Instant::now(),
-            channels: Vec::new(),
-            total_beats: (beats * sub_beats) as usize,
-            tx,
-        }
+    if let Some(path) = arg("--play") {
+        Smf::load(&path)
+            .unwrap_or_else(|e| panic!("failed to load midi file {path}: {e}"))
+            .play(midi::ChannelMap::omni(0), tx.clone());
     }
 
-    fn update(&mut self) {
-        if Instant::now().duration_since(self.last_time) >= self.beat_duration {
-            // print!("current_beat = {}", self.current_beat);
-
-            for &(ch, mask) in &self.channels {
-                if mask & (1 << self.current_beat) != 0 {
-                    // print!(" *");
-                    _ = self.tx.send(Event::Trigger(ch));
-                }
-            }
-            // println!();
-            self.last_time += self.beat_duration;
-            self.current_beat = (self.current_beat + 1) % self.total_beats;
+    // e.g. `--layout azerty.txt`, see `kbd::Keyboard::parse` for the format
+    let mut keyboard = match arg("--layout") {
+        Some(path) => {
+            Keyboard::load(&path).unwrap_or_else(|e| panic!("failed to load layout {path}: {e}"))
         }
-    }
+        None => Keyboard::new(),
+    };
 
-    fn add_channel(&mut self, inst_id: usize, pat: &str) {
-        assert_eq!(pat.len(), self.total_beats);
+    let mut seq = Sequencer::new(60.0, 4, 4, tx.clone());
+    // seq.add_channel(1, "x...x...x...x...");
+    // seq.add_channel(2, ".xxx.xxx.xxx.xxx");
+    // seq.add_channel(3, "x.x.x.x.x.x.x.x.");
 
-        let mut mask = 0u16;
-        for (i, chr) in pat.char_indices() {
-            if chr == 'x' {
-                mask |= 1 << i;
-            }
-        }
+    seq.add_channel(3, "x...x...x...x...");
+    seq.add_channel(1, ".xxx.xxx.xxx.xxx");
 
-        // println!("{inst_id} {pat} {mask:016b} {}", mask.count_ones());
-        self.channels.push((inst_id, mask));
+    // A fill, switch to it live or arrange it with `--song "A*3 B"`
+    let fill = seq.add_pattern("B", 1);
+    seq.patterns[fill].channels = vec![
+        Track::parse(3, "x...x...x.x.xxxx"),
+        Track::parse(1, ".xxx.xxx.xxxx.x."),
+    ];
+    if let Some(song) = arg("--song") {
+        seq.arrange(&song)
+            .unwrap_or_else(|e| panic!("invalid --song {song}: {e}"));
     }
-}
-
-const SAMPLE_RATE: f64 = 44_100.0;
-
-fn main() {
-    let (tx, rx) = mpsc::channel();
-
-    let instrument = Instrument::builder()
-        .lfo(Waveform::Sine, 3.0, 0.02)
-        .osc(Waveform::Sine, 1.0)
-        .osc(Waveform::Saw, 0.2)
-        .env(0.002, 0.1, 0.8, 0.2)
-        .build();
-
-    let instruments = vec![instrument, preset::kick(), preset::snare(), preset::hihat()];
 
-    let mut synth = Synth::<32>::new(rx, instruments);
-    let engine = Engine::new(SAMPLE_RATE, move |buf| synth.process(buf));
+    // e.g. `--swing 0.33` for a triplet shuffle
+    if let Some(swing) = arg("--swing") {
+        seq.swing = swing
+            .parse()
+            .unwrap_or_else(|e| panic!("invalid --swing {swing}: {e}"));
+    }
 
-    engine.start();
+    if clock_in {
+        seq.source = ClockSource::Midi;
+    }
 
-    let mut keyboard = Keyboard::new();
+    // e.g. `--clock-out /dev/snd/midiC1D0` to drive other gear at our tempo
+    if let Some(path) = arg("--clock-out") {
+        let out = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap_or_else(|e| panic!("failed to open midi output {path}: {e}"));
+        seq.clock_out(out);
+    }
 
-    let mut seq = Sequencer::new(60.0, 4, 4, tx.clone());
-    // seq.add_channel(1, "x...x...x...x...");
-    // seq.add_channel(2, ".xxx.xxx.xxx.xxx");
-    // seq.add_channel(3, "x.x.x.x.x.x.x.x.");
+    // Instrument `n` on MIDI channel `n`
+    let channels = midi::ChannelMap::instruments(instrument_count);
 
-    seq.add_channel(3, "x...x...x...x...");
-    seq.add_channel(1, ".xxx.xxx.xxx.xxx");
+    if let Some(path) = arg("--export") {
+        // The whole song, or a few bars of the pattern without one
+        let bars = match seq.song_bars() {
+            0 => 4,
+            bars => bars,
+        };
+        seq.export(bars, &channels)
+            .save(&path)
+            .unwrap_or_else(|e| panic!("failed to export {path}: {e}"));
+    }
 
-    ratatui::init();
+    // e.g. `--arp up-down --arp-octaves 2 --arp-rate 0.5 --arp-gate 0.8 --latch`,
+    // see `arp::order_from_name`
+    let mut arp = arg("--arp").map(|name| {
+        let order =
+            arp::order_from_name(&name).unwrap_or_else(|| panic!("invalid --arp order {name}"));
+        let mut arp = Arpeggiator::new(order);
+        if let Some(octaves) = arg("--arp-octaves") {
+            arp.octaves = octaves
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid --arp-octaves {octaves}: {e}"));
+        }
+        if let Some(rate) = arg("--arp-rate") {
+            arp.rate = rate
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid --arp-rate {rate}: {e}"));
+        }
+        if let Some(gate) = arg("--arp-gate") {
+            arp.gate = gate
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid --arp-gate {gate}: {e}"));
+        }
+        arp.set_latch(std::env::args().any(|arg| arg == "--latch"));
+        arp
+    });
+
+    // Only kept with `--record`, it grows with every note played
+    let mut recorder = arg("--record").map(|path| (path, smf::Recorder::new()));
+
+    let mut terminal = ratatui::init();
+    let mut drawn = Instant::now();
+
+    // Without release events a key counts as held while it keeps repeating.
+    // The wait has to outlast the key repeat delay (660 ms on Xorg), set it
+    // in ms with `--release-after`
+    let releases = kbd::enable_releases();
+    if !releases {
+        let millis = arg("--release-after").map_or(750, |ms| {
+            ms.parse()
+                .unwrap_or_else(|e| panic!("invalid --release-after {ms}: {e}"))
+        });
+        keyboard.release_after = Some(Duration::from_millis(millis));
+    }
 
     loop {
+        for msg in clock_rx.try_iter() {
+            seq.clock(msg);
+        }
         seq.update();
 
-        for (note, key) in keyboard.keys.iter_mut().enumerate() {
-            let down = kbd::is_key_down(key.code);
-
-            if down && !key.pressed {
-                key.pressed = true;
-                _ = tx.send(Event::NoteOn(0, note as u8));
+        let mut keys = Vec::new();
+        if term::poll(Duration::from_millis(2)).unwrap_or(false)
+            && let Ok(term::Event::Key(key)) = term::read()
+        {
+            // Esc and Ctrl-C always quit, `q` unless the layout plays it
+            let quit = match key.code {
+                KeyCode::Esc => true,
+                KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+                KeyCode::Char('q') => !keyboard.keys.iter().any(|k| k.code == key.code),
+                _ => false,
+            };
+            if key.kind == KeyEventKind::Press && quit {
+                break;
             }
-
-            if !down && key.pressed {
-                key.pressed = false;
-                _ = tx.send(Event::NoteOff(0, note as u8));
+            // Backspace remembers the held keys as the chord, or forgets it
+            if key.code == KeyCode::Backspace {
+                if key.kind == KeyEventKind::Press {
+                    let held: Vec<u8> = keyboard.held().collect();
+                    harmonizer.lock().unwrap().memorize(&held);
+                }
+            } else if !app.key_event(key, &mut seq) {
+                keys.extend(keyboard.key_event(key, Instant::now()));
             }
         }
+        while let Some(note) = keyboard.expire(Instant::now()) {
+            keys.push(Note::Off(note));
+        }
+
+        // Held keys feed the arpeggiator instead of playing
+        let notes = match &mut arp {
+            Some(arp) => {
+                keys.into_iter().for_each(|note| arp.input(note));
+                let mut notes = Vec::new();
+                arp.update(seq.position(), &mut notes);
+                notes
+            }
+            None => keys,
+        };
 
-        if kbd::is_key_down(KeyCode::Q) {
-            break;
+        // ~30 fps
+        if drawn.elapsed() >= Duration::from_millis(33) {
+            drawn = Instant::now();
+            _ = terminal.draw(|frame| app.draw(frame, &keyboard, &seq));
         }
 
-        thread::sleep(Duration::from_millis(2));
+        let mut events = Vec::new();
+        for note in notes {
+            let event = match note {
+                Note::On(note) => Event::NoteOn(0, note, DEFAULT_VELOCITY),
+                Note::Off(note) => Event::NoteOff(0, note),
+            };
+            harmonizer.lock().unwrap().process(event, &mut events);
+        }
+        for event in events {
+            if let Some((_, recorder)) = &mut recorder {
+                recorder.record(event);
+            }
+            _ = tx.send(event);
+        }
     }
 
     engine.stop();
 
+    if releases {
+        kbd::disable_releases();
+    }
     ratatui::restore();
+
+    if let Some((path, recorder)) = recorder {
+        let mut writer = smf::Writer::new(seq.bpm(), seq.beats);
+        recorder.write(&mut writer, "keyboard", &channels);
+        writer
+            .save(&path)
+            .unwrap_or_else(|e| panic!("failed to save recording {path}: {e}"));
+    }
 }
@@ -0,0 +1,529 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::Event;
+
+/// A MIDI 1.0 channel or system (clock) message, channels are 0..16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyAftertouch {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        control: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelAftertouch {
+        channel: u8,
+        pressure: u8,
+    },
+    /// -8192..8192, 0 is centered
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+    /// 24 per quarter note
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    /// In 16th notes (6 clocks) from the start of the song
+    SongPosition(u16),
+}
+
+impl Message {
+    /// `None` for system messages.
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            Self::NoteOff { channel, .. }
+            | Self::NoteOn { channel, .. }
+            | Self::PolyAftertouch { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::ChannelAftertouch { channel, .. }
+            | Self::PitchBend { channel, .. } => Some(channel),
+            Self::Clock | Self::Start | Self::Continue | Self::Stop | Self::SongPosition(_) => None,
+        }
+    }
+
+    /// Append the wire bytes of this message to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let ch = self.channel().unwrap_or(0) & 0x0F;
+        match *self {
+            Self::NoteOff { note, velocity, .. } => out.extend([0x80 | ch, note, velocity]),
+            Self::NoteOn { note, velocity, .. } => out.extend([0x90 | ch, note, velocity]),
+            Self::PolyAftertouch { note, pressure, .. } => out.extend([0xA0 | ch, note, pressure]),
+            Self::ControlChange { control, value, .. } => out.extend([0xB0 | ch, control, value]),
+            Self::ProgramChange { program, .. } => out.extend([0xC0 | ch, program]),
+            Self::ChannelAftertouch { pressure, .. } => out.extend([0xD0 | ch, pressure]),
+            Self::PitchBend { value, .. } => {
+                let value = (value.clamp(-8192, 8191) + 8192) as u16;
+                out.extend([0xE0 | ch, (value & 0x7F) as u8, (value >> 7) as u8])
+            }
+            Self::Clock => out.push(0xF8),
+            Self::Start => out.push(0xFA),
+            Self::Continue => out.push(0xFB),
+            Self::Stop => out.push(0xFC),
+            Self::SongPosition(pos) => out.extend([0xF2, (pos & 0x7F) as u8, (pos >> 7) as u8]),
+        }
+    }
+}
+
+/// Number of data bytes following a status byte.
+pub(crate) fn data_len(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        0xF0 => match status {
+            0xF1 | 0xF3 => 1,
+            0xF2 => 2,
+            _ => 0,
+        },
+        _ => 2,
+    }
+}
+
+/// Incremental MIDI 1.0 byte stream parser, handles running status and
+/// interleaved real-time bytes, skips SysEx.
+#[derive(Default)]
+pub struct Parser {
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one byte, returns a message once one is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Message> {
+        match byte {
+            // Real-time, may appear anywhere without touching running status
+            0xF8 => Some(Message::Clock),
+            0xFA => Some(Message::Start),
+            0xFB => Some(Message::Continue),
+            0xFC => Some(Message::Stop),
+            0xF9..=0xFF => None,
+            // SysEx end or undefined, clears running status
+            0xF4..=0xF7 => {
+                self.status = None;
+                None
+            }
+            0x80..=0xF3 => {
+                // SysEx data is skipped until the next status byte
+                self.status = (byte != 0xF0).then_some(byte);
+                self.len = 0;
+                None
+            }
+            _ => {
+                let status = self.status?;
+                self.data[self.len] = byte;
+                self.len += 1;
+
+                if self.len < data_len(status) {
+                    return None;
+                }
+                self.len = 0;
+
+                if status >= 0xF0 {
+                    // System common, no running status
+                    self.status = None;
+                    let [lsb, msb] = self.data;
+                    return (status == 0xF2)
+                        .then_some(Message::SongPosition((msb as u16) << 7 | lsb as u16));
+                }
+
+                Some(self.message(status))
+            }
+        }
+    }
+
+    fn message(&self, status: u8) -> Message {
+        let channel = status & 0x0F;
+        let [a, b] = self.data;
+        match status & 0xF0 {
+            0x80 => Message::NoteOff {
+                channel,
+                note: a,
+                velocity: b,
+            },
+            0x90 if b == 0 => Message::NoteOff {
+                channel,
+                note: a,
+                velocity: 64,
+            },
+            0x90 => Message::NoteOn {
+                channel,
+                note: a,
+                velocity: b,
+            },
+            0xA0 => Message::PolyAftertouch {
+                channel,
+                note: a,
+                pressure: b,
+            },
+            0xB0 => Message::ControlChange {
+                channel,
+                control: a,
+                value: b,
+            },
+            0xC0 => Message::ProgramChange {
+                channel,
+                program: a,
+            },
+            0xD0 => Message::ChannelAftertouch {
+                channel,
+                pressure: a,
+            },
+            _ => Message::PitchBend {
+                channel,
+                value: ((b as i16) << 7 | a as i16) - 8192,
+            },
+        }
+    }
+}
+
+/// An MPE zone, a master channel for zone wide messages plus member
+/// channels each carrying a single note and its own expression.
+#[derive(Debug, Clone, Copy)]
+pub struct Zone {
+    pub master: u8,
+    /// Number of member channels, 1..=15
+    pub members: u8,
+    pub inst: usize,
+}
+
+impl Zone {
+    /// Master on channel 1, members counting up from channel 2.
+    pub fn lower(members: u8, inst: usize) -> Self {
+        Self {
+            master: 0,
+            members: members.clamp(1, 15),
+            inst,
+        }
+    }
+
+    /// Master on channel 16, members counting down from channel 15.
+    pub fn upper(members: u8, inst: usize) -> Self {
+        Self {
+            master: 15,
+            members: members.clamp(1, 15),
+            inst,
+        }
+    }
+
+    pub fn is_member(&self, channel: u8) -> bool {
+        if self.master == 0 {
+            (1..=self.members).contains(&channel)
+        } else {
+            (15 - self.members..15).contains(&channel)
+        }
+    }
+}
+
+/// Routes MIDI channels to instrument indices.
+#[derive(Clone, Copy)]
+pub struct ChannelMap {
+    pub channels: [Option<usize>; 16],
+    /// MPE lower and upper zones, taking precedence over `channels`.
+    pub zones: [Option<Zone>; 2],
+}
+
+impl Default for ChannelMap {
+    /// Channel `n` plays instrument `n`.
+    fn default() -> Self {
+        Self {
+            channels: std::array::from_fn(Some),
+            zones: [None; 2],
+        }
+    }
+}
+
+impl ChannelMap {
+    /// Channel `n` plays instrument `n` for the first `count` channels, the
+    /// rest are ignored.
+    pub fn instruments(count: usize) -> Self {
+        Self {
+            channels: std::array::from_fn(|ch| (ch < count).then_some(ch)),
+            zones: [None; 2],
+        }
+    }
+
+    /// Every channel plays `inst`.
+    pub fn omni(inst: usize) -> Self {
+        Self {
+            channels: [Some(inst); 16],
+            zones: [None; 2],
+        }
+    }
+
+    /// Add an MPE zone, replacing any zone on the same side.
+    pub fn zone(mut self, zone: Zone) -> Self {
+        self.zones[(zone.master != 0) as usize] = Some(zone);
+        self
+    }
+
+    pub fn event(&self, msg: Message) -> Option<Event> {
+        let ch = msg.channel()?;
+        for zone in self.zones.iter().flatten() {
+            if ch == zone.master {
+                return Some(Self::channel_event(zone.inst, msg));
+            }
+            if zone.is_member(ch) {
+                return Some(Self::member_event(zone.inst, msg));
+            }
+        }
+
+        let inst = self.channels[ch as usize]?;
+        Some(Self::channel_event(inst, msg))
+    }
+
+    /// Per note expression on an MPE member channel.
+    fn member_event(inst: usize, msg: Message) -> Event {
+        match msg {
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => Event::MemberNoteOn(inst, channel, note, velocity),
+            Message::NoteOff { channel, note, .. } => Event::MemberNoteOff(inst, channel, note),
+            Message::PitchBend { channel, value } => Event::MemberBend(inst, channel, value),
+            Message::ChannelAftertouch { channel, pressure } => {
+                Event::MemberPressure(inst, channel, pressure)
+            }
+            Message::ControlChange {
+                channel,
+                control: 74,
+                value,
+            } => Event::MemberTimbre(inst, channel, value),
+            _ => Self::channel_event(inst, msg),
+        }
+    }
+
+    fn channel_event(inst: usize, msg: Message) -> Event {
+        match msg {
+            Message::NoteOn { note, velocity, .. } => Event::NoteOn(inst, note, velocity),
+            Message::NoteOff { note, .. } => Event::NoteOff(inst, note),
+            Message::PolyAftertouch { note, pressure, .. } => {
+                Event::PolyAftertouch(inst, note, pressure)
+            }
+            Message::ControlChange { control, value, .. } => {
+                Event::ControlChange(inst, control, value)
+            }
+            Message::ProgramChange { program, .. } => Event::ProgramChange(inst, program),
+            Message::ChannelAftertouch { pressure, .. } => Event::Aftertouch(inst, pressure),
+            Message::PitchBend { value, .. } => Event::PitchBend(inst, value),
+            Message::Clock
+            | Message::Start
+            | Message::Continue
+            | Message::Stop
+            | Message::SongPosition(_) => unreachable!("system messages have no channel"),
+        }
+    }
+
+    /// A [spawn] handler sending mapped [Event]s to `tx`, ignoring system messages.
+    pub fn sender(self, tx: mpsc::Sender<Event>) -> impl FnMut(Message) -> bool + Send + 'static {
+        move |msg| self.event(msg).is_none_or(|event| tx.send(event).is_ok())
+    }
+
+    /// The inverse of [ChannelMap::event], on the first channel mapped to the instrument.
+    /// Triggers become [crate::smf::TRIGGER_NOTE].
+    pub fn message(&self, event: Event) -> Option<Message> {
+        let channel = match event {
+            Event::MemberNoteOn(_, ch, ..)
+            | Event::MemberNoteOff(_, ch, _)
+            | Event::MemberBend(_, ch, _)
+            | Event::MemberPressure(_, ch, _)
+            | Event::MemberTimbre(_, ch, _) => ch,
+            _ => self
+                .channels
+                .iter()
+                .position(|&c| c == Some(event.inst()))? as u8,
+        };
+
+        Some(match event {
+            Event::NoteOn(_, note, velocity) => Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            },
+            Event::NoteOff(_, note) => Message::NoteOff {
+                channel,
+                note,
+                velocity: 64,
+            },
+            Event::Trigger(_, velocity) => Message::NoteOn {
+                channel,
+                note: crate::smf::TRIGGER_NOTE,
+                velocity,
+            },
+            Event::ControlChange(_, control, value) => Message::ControlChange {
+                channel,
+                control,
+                value,
+            },
+            Event::PitchBend(_, value) => Message::PitchBend { channel, value },
+            Event::Aftertouch(_, pressure) => Message::ChannelAftertouch { channel, pressure },
+            Event::PolyAftertouch(_, note, pressure) => Message::PolyAftertouch {
+                channel,
+                note,
+                pressure,
+            },
+            Event::ProgramChange(_, program) => Message::ProgramChange { channel, program },
+            Event::MemberNoteOn(_, _, note, velocity) => Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            },
+            Event::MemberNoteOff(_, _, note) => Message::NoteOff {
+                channel,
+                note,
+                velocity: 64,
+            },
+            Event::MemberBend(_, _, value) => Message::PitchBend { channel, value },
+            Event::MemberPressure(_, _, pressure) => {
+                Message::ChannelAftertouch { channel, pressure }
+            }
+            Event::MemberTimbre(_, _, value) => Message::ControlChange {
+                channel,
+                control: 74,
+                value,
+            },
+            Event::Param(..) | Event::Tuning(..) => return None,
+        })
+    }
+}
+
+/// Read a MIDI byte stream on a background thread, passing every message to
+/// `handler` until the stream ends or the handler returns `false`.
+///
+/// Works with anything readable: a Linux rawmidi device (`/dev/snd/midiC1D0`,
+/// including `snd-virmidi` ports wired up through the ALSA sequencer), a
+/// named pipe, a file or stdin.
+pub fn spawn<R, F>(mut reader: R, mut handler: F) -> thread::JoinHandle<io::Result<()>>
+where
+    R: Read + Send + 'static,
+    F: FnMut(Message) -> bool + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut parser = Parser::new();
+        let mut buf = [0u8; 256];
+
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for &byte in &buf[..n] {
+                if let Some(msg) = parser.push(byte)
+                    && !handler(msg)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    })
+}
+
+/// Open a MIDI device or file by path, `-` reads stdin.
+pub fn open<F>(path: impl AsRef<Path>, handler: F) -> io::Result<thread::JoinHandle<io::Result<()>>>
+where
+    F: FnMut(Message) -> bool + Send + 'static,
+{
+    let path = path.as_ref();
+    if path == Path::new("-") {
+        return Ok(spawn(io::stdin(), handler));
+    }
+    Ok(spawn(File::open(path)?, handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Message> {
+        let mut parser = Parser::new();
+        bytes.iter().filter_map(|&byte| parser.push(byte)).collect()
+    }
+
+    fn note_on(note: u8, velocity: u8) -> Message {
+        Message::NoteOn {
+            channel: 1,
+            note,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 64, 90, 67, 80]),
+            [note_on(60, 100), note_on(64, 90), note_on(67, 80)]
+        );
+    }
+
+    #[test]
+    fn real_time_inside_message() {
+        assert_eq!(
+            parse(&[0x91, 60, 0xF8, 100, 62, 0xFA, 101]),
+            [
+                Message::Clock,
+                note_on(60, 100),
+                Message::Start,
+                note_on(62, 101)
+            ]
+        );
+    }
+
+    #[test]
+    fn sysex_skipped() {
+        assert_eq!(
+            parse(&[0xF0, 0x7E, 0x10, 0x20, 0xF7, 0x91, 60, 100]),
+            [note_on(60, 100)]
+        );
+        // SysEx cancels running status, data after it waits for a status byte
+        assert_eq!(
+            parse(&[0x91, 60, 100, 0xF0, 1, 2, 0xF7, 61, 100]),
+            [note_on(60, 100)]
+        );
+    }
+
+    #[test]
+    fn zero_velocity_note_off() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 60, 0]),
+            [
+                note_on(60, 100),
+                Message::NoteOff {
+                    channel: 1,
+                    note: 60,
+                    velocity: 64
+                }
+            ]
+        );
+    }
+}
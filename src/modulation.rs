@@ -0,0 +1,62 @@
+/// Where a modulation value (0..1) comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Note velocity after the instrument's [crate::preset::Velocity] curve.
+    Velocity,
+    /// -1..1, before the instrument's bend range
+    PitchBend,
+    /// CC 1
+    ModWheel,
+    /// Channel pressure, shared by every note of the instrument
+    Aftertouch,
+    /// Polyphonic key pressure of the voice's own note, or its MPE pressure
+    PolyPressure,
+    /// MPE per note timbre, CC 74 on the note's member channel
+    Timbre,
+}
+
+/// What a modulation value changes, scaled by the route amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Semitones
+    Pitch,
+    /// Gain, on top of the unmodulated 1.0
+    Amp,
+    /// Octaves, needs a filter on the instrument
+    Cutoff,
+    /// Scales the LFOs, on top of their own depth
+    LfoDepth,
+}
+
+/// Time (secs) for controllers to settle on a new value.
+const SMOOTH_TIME: f64 = 0.005;
+
+/// One pole smoothing of a control value, so stepped MIDI values
+/// don't cause zipper noise.
+#[derive(Default, Clone, Copy)]
+pub struct Smooth {
+    pub target: f64,
+    value: f64,
+}
+
+impl Smooth {
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// Jump to `value` right away.
+    pub fn reset(&mut self, value: f64) {
+        self.target = value;
+        self.value = value;
+    }
+
+    /// Per sample coefficient for [Smooth::next] at sample rate `sr`.
+    pub fn coef(sr: f64) -> f64 {
+        1.0 - (-1.0 / (SMOOTH_TIME * sr)).exp()
+    }
+
+    pub fn next(&mut self, coef: f64) -> f64 {
+        self.value += (self.target - self.value) * coef;
+        self.value
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{Hz, consts::TAU};
 
-#[derive(Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Waveform {
     #[default]
     Sine,
@@ -31,11 +31,20 @@ impl Osc {
         }
     }
 
+    pub fn set_freq(&mut self, freq: Hz, sr: f64) {
+        self.base_increment = freq.0 / sr;
+    }
+
     /// lfo value expected in range [-1, 1] scaled by gain
     pub fn mod_freq(&mut self, lfo: f64) {
         self.increment = self.base_increment * (1.0 + lfo);
     }
 
+    /// Multiply the base frequency by `ratio`.
+    pub fn mod_ratio(&mut self, ratio: f64) {
+        self.increment = self.base_increment * ratio;
+    }
+
     pub fn next(&mut self) -> f64 {
         let out = match self.waveform {
             Waveform::Sine => (self.phase * TAU).sin(),
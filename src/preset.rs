@@ -1,3 +1,7 @@
+use std::sync::Arc;
+
+use crate::modulation::{Source, Target};
+use crate::tuning::Tuning;
 use crate::{Hz, env, osc::Waveform};
 
 #[derive(Clone, Copy, PartialEq, Default)]
@@ -7,28 +11,173 @@ pub enum Kind {
     Percussive(Hz),
 }
 
+/// How notes of an instrument share voices.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Mode {
+    /// Every note gets its own voice.
+    #[default]
+    Poly,
+    /// One voice, every new note retriggers the envelope.
+    Mono,
+    /// One voice, notes played while another is held only change pitch.
+    Legato,
+}
+
+/// Which held note sounds in [Mode::Mono] and [Mode::Legato].
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Priority {
+    #[default]
+    Last,
+    Low,
+    High,
+}
+
+impl Priority {
+    /// Pick the sounding note out of the held (note, velocity) stack, oldest first.
+    pub fn pick(self, held: &[(u8, u8)]) -> Option<(u8, u8)> {
+        match self {
+            Self::Last => held.last().copied(),
+            Self::Low => held.iter().min_by_key(|(note, _)| *note).copied(),
+            Self::High => held.iter().max_by_key(|(note, _)| *note).copied(),
+        }
+    }
+}
+
+/// Portamento, how long a voice takes to slide between notes.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Glide {
+    #[default]
+    Off,
+    /// Same time (secs) for any interval.
+    Time(f64),
+    /// Time (secs) per octave travelled.
+    Rate(f64),
+}
+
+impl Glide {
+    /// Seconds to slide from one frequency to another.
+    pub fn time(self, from: Hz, to: Hz) -> f64 {
+        match self {
+            Self::Off => 0.0,
+            Self::Time(secs) => secs,
+            Self::Rate(secs) => secs * (to.0 / from.0).log2().abs(),
+        }
+    }
+}
+
+/// Maps note velocity (0..127) to a level (0..1).
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Velocity {
+    #[default]
+    Linear,
+    /// Exponent on the linear level, > 1.0 needs harder playing.
+    Curve(f64),
+    /// Ignore velocity, always this level.
+    Fixed(f64),
+}
+
+impl Velocity {
+    pub fn level(self, vel: u8) -> f64 {
+        let x = vel.min(127) as f64 / 127.0;
+        match self {
+            Self::Linear => x,
+            Self::Curve(exp) => x.powf(exp),
+            Self::Fixed(level) => level,
+        }
+    }
+}
+
+/// A live edit of an [Instrument], heard from the next note on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Param {
+    /// ADSR times (secs), ignored by multi-stage envelopes.
+    Attack(f64),
+    Decay(f64),
+    Sustain(f64),
+    Release(f64),
+    /// Index, waveform, gain
+    Osc(usize, Waveform, f64),
+}
+
 /// An instrument is just a preset for a runtime [Voice].
 pub struct Instrument {
     pub kind: Kind,
-    /// The [crate::env::Shape] of an ADSL [crate::env::Env].
-    pub shape: env::Shape,
+    /// The [crate::env::Kind] of envelope, either ADSR or multi-stage.
+    pub env: env::Kind,
     /// [OscKind]s and gains to construct an [crate::osc::Osc].
     pub oscs: Vec<(Waveform, f64)>,
     pub lfos: Vec<(Waveform, f64, f64)>, // form, freq, gain
+    pub mode: Mode,
+    pub priority: Priority,
+    pub glide: Glide,
+    pub velocity: Velocity,
+    /// How much velocity shortens the envelope times, 0..1.
+    pub velocity_time: f64,
+    pub filter: Option<(f64, f64)>,       // cutoff, resonance
+    pub mods: Vec<(Source, Target, f64)>, // source, target, amount
+    /// Semitones at full pitch bend.
+    pub bend_range: f64,
+    /// Semitones at full MPE per note bend.
+    pub member_bend_range: f64,
+    /// Most voices this instrument may play at once.
+    pub max_voices: Option<usize>,
+    /// Voices other instruments can't steal from this one.
+    pub reserve: usize,
+    /// Starting a note cuts voices of other instruments in the same group.
+    pub choke: Option<u8>,
+    /// Note to frequency mapping, 12-EDO at the synth's A4 if `None`.
+    /// Shared, so switching with [Event::Tuning] doesn't allocate.
+    ///
+    /// [Event::Tuning]: crate::Event::Tuning
+    pub tuning: Option<Arc<Tuning>>,
 }
 
 impl Instrument {
     pub fn builder() -> Builder {
         Builder::default()
     }
+
+    pub fn set(&mut self, param: Param) {
+        if let Param::Osc(index, waveform, gain) = param {
+            if let Some(osc) = self.oscs.get_mut(index) {
+                *osc = (waveform, gain);
+            }
+            return;
+        }
+
+        let env::Kind::Adsr(shape) = &mut self.env else {
+            return;
+        };
+        match param {
+            Param::Attack(secs) => shape.attack = secs,
+            Param::Decay(secs) => shape.decay = secs,
+            Param::Sustain(level) => shape.sustain = level,
+            Param::Release(secs) => shape.release = secs,
+            Param::Osc(..) => {}
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct Builder {
     kind: Kind,
-    shape: env::Shape,
+    env: env::Kind,
+    oneshot: bool,
     oscs: Vec<(Waveform, f64)>,
     pub lfos: Vec<(Waveform, f64, f64)>,
+    mode: Mode,
+    priority: Priority,
+    glide: Glide,
+    velocity: Velocity,
+    velocity_time: f64,
+    filter: Option<(f64, f64)>,
+    mods: Vec<(Source, Target, f64)>,
+    bend_range: Option<f64>,
+    member_bend_range: Option<f64>,
+    max_voices: Option<usize>,
+    reserve: usize,
+    choke: Option<u8>,
+    tuning: Option<Tuning>,
 }
 
 impl Builder {
@@ -43,18 +192,25 @@ impl Builder {
     }
 
     pub fn env(mut self, a: f64, d: f64, s: f64, r: f64) -> Self {
-        self.shape = env::Shape {
+        self.env = env::Kind::Adsr(env::Shape {
             attack: a,
             decay: d,
             sustain: s,
             release: r,
             hold: true,
-        };
+        });
         self
     }
 
+    /// Use a multi-stage [env::Stages] envelope instead of an ADSR.
+    pub fn stages(mut self, stages: env::Stages) -> Self {
+        self.env = env::Kind::Stages(stages);
+        self
+    }
+
+    /// Don't sustain, run through the envelope without waiting for `note_off`.
     pub fn oneshot(mut self) -> Self {
-        self.shape.hold = false;
+        self.oneshot = true;
         self
     }
 
@@ -68,12 +224,105 @@ impl Builder {
         self
     }
 
-    pub fn build(self) -> Instrument {
+    pub fn mono(mut self) -> Self {
+        self.mode = Mode::Mono;
+        self
+    }
+
+    /// Mono, but overlapping notes glide without retriggering the envelope.
+    pub fn legato(mut self) -> Self {
+        self.mode = Mode::Legato;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn glide(mut self, glide: Glide) -> Self {
+        self.glide = glide;
+        self
+    }
+
+    pub fn velocity(mut self, curve: Velocity) -> Self {
+        self.velocity = curve;
+        self
+    }
+
+    /// Harder notes get shorter attack/decay, `amount` 0..1.
+    pub fn velocity_time(mut self, amount: f64) -> Self {
+        self.velocity_time = amount;
+        self
+    }
+
+    /// Low-pass the voice at `cutoff` Hz, `resonance` 0..1.
+    pub fn filter(mut self, cutoff: f64, resonance: f64) -> Self {
+        self.filter = Some((cutoff, resonance));
+        self
+    }
+
+    pub fn route(mut self, source: Source, target: Target, amount: f64) -> Self {
+        self.mods.push((source, target, amount));
+        self
+    }
+
+    pub fn bend_range(mut self, semitones: f64) -> Self {
+        self.bend_range = Some(semitones);
+        self
+    }
+
+    pub fn member_bend_range(mut self, semitones: f64) -> Self {
+        self.member_bend_range = Some(semitones);
+        self
+    }
+
+    pub fn max_voices(mut self, max: usize) -> Self {
+        self.max_voices = Some(max);
+        self
+    }
+
+    pub fn reserve(mut self, voices: usize) -> Self {
+        self.reserve = voices;
+        self
+    }
+
+    pub fn choke(mut self, group: u8) -> Self {
+        self.choke = Some(group);
+        self
+    }
+
+    pub fn tuning(mut self, tuning: Tuning) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+
+    pub fn build(mut self) -> Instrument {
+        if self.oneshot {
+            match &mut self.env {
+                env::Kind::Adsr(shape) => shape.hold = false,
+                env::Kind::Stages(stages) => stages.sustain = None,
+            }
+        }
+
         Instrument {
             kind: self.kind,
-            shape: self.shape,
+            env: self.env,
             oscs: self.oscs,
             lfos: self.lfos,
+            mode: self.mode,
+            priority: self.priority,
+            glide: self.glide,
+            velocity: self.velocity,
+            velocity_time: self.velocity_time,
+            filter: self.filter,
+            mods: self.mods,
+            bend_range: self.bend_range.unwrap_or(2.0),
+            member_bend_range: self.member_bend_range.unwrap_or(48.0),
+            max_voices: self.max_voices,
+            reserve: self.reserve,
+            choke: self.choke,
+            tuning: self.tuning.map(Arc::new),
         }
     }
 }
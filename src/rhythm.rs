@@ -0,0 +1,116 @@
+use rand::rngs::SmallRng;
+use rand::{RngExt, SeedableRng};
+
+use crate::seq::Step;
+
+/// `hits` spread as evenly as they go over `steps`, rotated left by
+/// `rotation`, as a pattern for [Sequencer::add_channel]. `euclid(3, 8, 0)`
+/// is the tresillo `x..x..x.`.
+///
+/// [Sequencer::add_channel]: crate::seq::Sequencer::add_channel
+pub fn euclid(hits: usize, steps: usize, rotation: usize) -> String {
+    let hits = hits.min(steps);
+    (0..steps)
+        .map(|i| match ((i + rotation) * hits) % steps < hits {
+            true => 'x',
+            false => '.',
+        })
+        .collect()
+}
+
+/// `x` as a chance for `random_bool`, which panics on NaN.
+fn chance(x: f64) -> f64 {
+    match x.is_finite() {
+        true => x.clamp(0.0, 1.0),
+        false => 0.0,
+    }
+}
+
+/// `steps` hitting with chance `density` each, the same for the same `seed`.
+pub fn random(steps: usize, density: f64, seed: u64) -> String {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let density = chance(density);
+    (0..steps)
+        .map(|_| match rng.random_bool(density) {
+            true => 'x',
+            false => '.',
+        })
+        .collect()
+}
+
+/// Varies a track while it plays, see [Track::mutation]. The written steps
+/// stay as they are, each variation is drawn afresh from them.
+///
+/// [Track::mutation]: crate::seq::Track::mutation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mutation {
+    /// Bars between changes.
+    pub every: usize,
+    /// Chance each step flips between a hit and a rest.
+    pub amount: f64,
+    pub seed: u64,
+    /// Steps flipped in the current variation.
+    flips: Vec<bool>,
+}
+
+impl Mutation {
+    pub fn new(every: usize, amount: f64, seed: u64) -> Self {
+        Self {
+            every,
+            amount,
+            seed,
+            flips: Vec::new(),
+        }
+    }
+
+    /// Draw the next variation of a track of `len` steps.
+    pub fn vary(&mut self, len: usize) {
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        let amount = chance(self.amount);
+        self.flips.clear();
+        self.flips.extend((0..len).map(|_| rng.random_bool(amount)));
+        self.seed = rng.random();
+    }
+
+    /// Step `index` of the written `steps` in the current variation. A rest
+    /// flipped to a hit copies the closest written hit before it, so notes
+    /// and velocities carry over.
+    pub fn step(&self, steps: &[Option<Step>], index: usize) -> Option<Step> {
+        let step = *steps.get(index)?;
+        if !self.flips.get(index).copied().unwrap_or(false) {
+            return step;
+        }
+        match step {
+            Some(_) => None,
+            None => {
+                let before = steps[..index]
+                    .iter()
+                    .rev()
+                    .chain(steps[index..].iter().rev());
+                Some(before.flatten().next().copied().unwrap_or_default())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn euclid_rhythms() {
+        assert_eq!(euclid(3, 8, 0), "x..x..x.");
+        assert_eq!(euclid(3, 8, 1), "..x..x.x");
+        assert_eq!(euclid(4, 16, 0), "x...x...x...x...");
+        assert_eq!(euclid(9, 4, 0), "xxxx");
+        assert_eq!(euclid(0, 4, 0), "....");
+    }
+
+    #[test]
+    fn nan_chances() {
+        assert_eq!(random(4, f64::NAN, 1), "....");
+        let mut mutation = Mutation::new(1, f64::NAN, 1);
+        mutation.vary(4);
+        assert_eq!(mutation.flips, [false; 4]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::f64::consts::TAU;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::filter::Filter;
+
+/// Lock-free ring of recent output, written by the audio thread and read by
+/// the UI. Readers may see a torn window while it is overwritten, which a
+/// scope can live with. Fed through a [Decimator] to keep it cheap.
+pub struct Scope {
+    samples: Box<[AtomicU32]>, // f32 bits
+    /// Samples written so far.
+    written: AtomicUsize,
+    /// Rate (Hz) of the samples.
+    pub sample_rate: f64,
+}
+
+impl Scope {
+    /// Keep the last `len` samples of a `sample_rate` stream.
+    pub fn new(len: usize, sample_rate: f64) -> Self {
+        Self {
+            samples: (0..len.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            sample_rate,
+        }
+    }
+
+    /// Only called from the one writer thread.
+    pub(crate) fn push(&self, sample: f32) {
+        let i = self.written.load(Ordering::Relaxed);
+        self.samples[i % self.samples.len()].store(sample.to_bits(), Ordering::Relaxed);
+        self.written.store(i + 1, Ordering::Release);
+    }
+
+    /// Copy the ring into `out`, oldest first.
+    pub fn read(&self, out: &mut Vec<f32>) {
+        let len = self.samples.len();
+        let end = self.written.load(Ordering::Acquire);
+        out.clear();
+        out.extend(
+            (end.saturating_sub(len)..end)
+                .map(|i| f32::from_bits(self.samples[i % len].load(Ordering::Relaxed))),
+        );
+    }
+}
+
+/// Keeps every `factor`th sample of a stream, low-passed first so nothing
+/// above the new Nyquist folds back into a [Scope]. Owned by the writer.
+pub struct Decimator {
+    factor: usize,
+    count: usize,
+    /// 4th order Butterworth at half the new Nyquist, two 2-pole sections.
+    filters: [Filter; 2],
+    cutoff: f64,
+}
+
+impl Decimator {
+    pub fn new(factor: usize, sample_rate: f64) -> Self {
+        let factor = factor.max(1);
+        Self {
+            factor,
+            count: 0,
+            filters: [
+                Filter::new(sample_rate, 0.076),
+                Filter::new(sample_rate, 0.617),
+            ],
+            cutoff: sample_rate / factor as f64 / 4.0,
+        }
+    }
+
+    /// Filter `sample`, returns it when it is one to keep.
+    pub fn next(&mut self, sample: f32) -> Option<f32> {
+        if self.factor == 1 {
+            return Some(sample);
+        }
+        let [a, b] = &mut self.filters;
+        let sample = b.next(a.next(sample as f64, self.cutoff), self.cutoff);
+
+        self.count = (self.count + 1) % self.factor;
+        (self.count == 0).then_some(sample as f32)
+    }
+}
+
+/// The last `width` samples starting at a rising zero crossing, so periodic
+/// waves hold still between frames.
+pub fn trigger(samples: &[f32], width: usize) -> &[f32] {
+    let Some(search) = samples.len().checked_sub(width) else {
+        return samples;
+    };
+    let start = (1..=search)
+        .rev()
+        .find(|&i| samples[i - 1] < 0.0 && samples[i] >= 0.0)
+        .unwrap_or(search);
+    &samples[start..start + width]
+}
+
+/// Magnitudes (dB, 0 for a full scale sine) of the first half of a Hann
+/// windowed FFT, over the largest power of two of `samples`.
+pub fn spectrum(samples: &[f32]) -> Vec<f64> {
+    if samples.is_empty() {
+        return Vec::new();
+    }
+    let n = 1 << samples.len().ilog2();
+    let samples = &samples[samples.len() - n..];
+
+    let window = |i: usize| 0.5 - 0.5 * (TAU * i as f64 / n as f64).cos();
+    let mut re: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| x as f64 * window(i))
+        .collect();
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+
+    // A Hann window halves the amplitude, a real sine splits over two bins
+    let scale = 4.0 / n as f64;
+    (0..n / 2)
+        .map(|k| {
+            let mag = re[k].hypot(im[k]) * scale;
+            20.0 * mag.max(1e-10).log10()
+        })
+        .collect()
+}
+
+/// In place radix-2 FFT, the length must be a power of two.
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let (sin, cos) = (-TAU / len as f64).sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut w_re, mut w_im) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+                (w_re, w_im) = (w_re * cos - w_im * sin, w_re * sin + w_im * cos);
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::mpsc;
+use std::time::Instant;
+
+use crate::harmony::pitch_class;
+use crate::midi::{ChannelMap, Message};
+use crate::rhythm::Mutation;
+use crate::smf::{self, Writer};
+use crate::{DEFAULT_VELOCITY, Event};
+
+/// MIDI clock pulses per quarter note.
+pub const PPQN: u32 = 24;
+
+/// Where the step timing comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClockSource {
+    #[default]
+    Internal,
+    /// Steps advance on incoming [Message::Clock] pulses fed to [Sequencer::clock].
+    Midi,
+}
+
+/// Steps scheduled ahead of the playhead, so negative offsets play on time.
+const LOOKAHEAD: f64 = 0.5;
+
+/// Shortest note length in steps, shorter gates are stretched to it.
+const MIN_GATE: f64 = 0.01;
+
+/// One hit of a [Track].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// `None` for a [Event::Trigger] of percussive instruments.
+    pub note: Option<u8>,
+    pub velocity: u8,
+    /// Note length in steps, split between ratchets.
+    pub gate: f64,
+    /// Chance the step plays, 0..1.
+    pub probability: f64,
+    /// Hits evenly spread over the step.
+    pub ratchet: u8,
+    /// Micro-timing in steps, -0.5..0.5.
+    pub offset: f64,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            note: None,
+            velocity: DEFAULT_VELOCITY,
+            gate: 0.5,
+            probability: 1.0,
+            ratchet: 1,
+            offset: 0.0,
+        }
+    }
+}
+
+/// A pattern for one instrument, `None` steps are rests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub inst: usize,
+    pub steps: Vec<Option<Step>>,
+    /// Speed against the sequencer, `multiply` track steps every `divide`
+    /// sequencer steps.
+    pub multiply: u32,
+    pub divide: u32,
+    /// Overrides [Sequencer::swing].
+    pub swing: Option<f64>,
+    /// Varies the steps while playing live, every so many bars.
+    pub mutation: Option<Mutation>,
+}
+
+impl Track {
+    /// A track of rests.
+    pub fn new(inst: usize, len: usize) -> Self {
+        Self {
+            inst,
+            steps: vec![None; len],
+            multiply: 1,
+            divide: 1,
+            swing: None,
+            mutation: None,
+        }
+    }
+
+    /// One step per char, `x` hits, `X` accents and anything else rests,
+    /// but a note name from octave 0 up like `C4` or `F#2` is a pitched step.
+    /// `C4.E4.G4.x.` plays an arpeggio and a trigger.
+    pub fn parse(inst: usize, pat: &str) -> Self {
+        let mut steps = Vec::new();
+        let mut chars = pat.chars().peekable();
+        while let Some(chr) = chars.next() {
+            steps.push(match chr {
+                'x' => Some(Step::default()),
+                'X' => Some(Step {
+                    velocity: 127,
+                    ..Default::default()
+                }),
+                'A'..='G' => {
+                    let mut name = chr.to_string();
+                    name.extend(chars.next_if(|&c| c == '#' || c == 'b'));
+                    let mut octave = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        octave.push(digit);
+                    }
+                    let note = octave
+                        .parse::<i32>()
+                        .ok()
+                        .zip(pitch_class(&name))
+                        .and_then(|(octave, pc)| u8::try_from((octave + 1) * 12 + pc as i32).ok())
+                        .filter(|&note| note < 128);
+                    note.map(|note| Step {
+                        note: Some(note),
+                        ..Default::default()
+                    })
+                }
+                _ => None,
+            });
+        }
+        Self {
+            steps,
+            ..Self::new(inst, 0)
+        }
+    }
+
+    /// Track step count at sequencer `position`, both in steps.
+    pub fn index(&self, position: f64) -> f64 {
+        position * self.multiply.max(1) as f64 / self.divide.max(1) as f64
+    }
+
+    /// Track steps scheduled along with sequencer step `index`, those whose
+    /// earliest time (half a track step early) is within [LOOKAHEAD] of it.
+    fn steps_in(&self, index: usize) -> Range<usize> {
+        let (m, d) = (self.multiply.max(1) as i64, self.divide.max(1) as i64);
+        // First k with (k - 1/2) * d/m >= i - 1/2, step 0 takes the earlier ones too
+        let first = |i: i64| match i {
+            0 => 0,
+            i => -(-((2 * i - 1) * m + d)).div_euclid(2 * d) as usize,
+        };
+        first(index as i64)..first(index as i64 + 1)
+    }
+
+    /// Step playing at step count `index`, the track loops over its own length.
+    pub fn step(&self, index: usize) -> Option<Step> {
+        let index = index.checked_rem(self.steps.len())?;
+        match &self.mutation {
+            Some(mutation) => mutation.step(&self.steps, index),
+            None => self.steps[index],
+        }
+    }
+
+    /// Push the events of track step count `index`, timed in sequencer steps,
+    /// rolling its probability.
+    fn events(&self, index: usize, swing: f64, out: &mut Vec<(f64, Event)>) {
+        let Some(step) = self.step(index) else {
+            return;
+        };
+        // The fields are public, skip a step no time can be made of
+        if ![step.gate, step.offset, step.probability, swing]
+            .iter()
+            .all(|x| x.is_finite())
+        {
+            return;
+        }
+        // A note off at or before its note on would sort first and stick
+        let gate = step.gate.max(MIN_GATE);
+        if !rand::random_bool(step.probability.clamp(0.0, 1.0)) {
+            return;
+        }
+
+        let scale = self.divide.max(1) as f64 / self.multiply.max(1) as f64;
+        let swing = if index % 2 == 1 {
+            self.swing.unwrap_or(swing)
+        } else {
+            0.0
+        };
+        let ratchet = step.ratchet.max(1) as f64;
+        for r in 0..step.ratchet.max(1) {
+            let time = (index as f64 + step.offset + swing + r as f64 / ratchet) * scale;
+            match step.note {
+                Some(note) => {
+                    out.push((time, Event::NoteOn(self.inst, note, step.velocity)));
+                    let off = time + gate / ratchet * scale;
+                    out.push((off, Event::NoteOff(self.inst, note)));
+                }
+                None => out.push((time, Event::Trigger(self.inst, step.velocity))),
+            }
+        }
+    }
+}
+
+/// Tracks played together, a part of a song.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub name: String,
+    pub channels: Vec<Track>,
+    /// Length in bars, tracks loop within it.
+    pub bars: usize,
+}
+
+impl Pattern {
+    pub fn new(name: &str, bars: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            channels: Vec::new(),
+            bars,
+        }
+    }
+}
+
+/// Where playback is in the arrangement.
+#[derive(Debug, Clone, Copy, Default)]
+struct Place {
+    pattern: usize,
+    /// Sequencer step the pattern started at.
+    start: usize,
+    /// Entry of [Sequencer::song] and how many times it has played.
+    entry: usize,
+    repeat: usize,
+    /// Playing a queued pattern, the song resumes where it was after it.
+    detour: bool,
+}
+
+/// Note offs go before note ons at the same time, so repeated notes retrigger.
+fn order(&(_, event): &(f64, Event)) -> bool {
+    !matches!(event, Event::NoteOff(..))
+}
+
+pub struct Sequencer {
+    bpm: f64,
+    pub beats: u8,
+    pub sub_beats: u8,
+    /// Length of new tracks, a bar.
+    pub total_beats: usize,
+    /// Delay of every second step in steps, 0 plays straight and 1/3 a
+    /// triplet shuffle.
+    pub swing: f64,
+    /// Playhead in steps, where `anchor` is.
+    position: f64,
+    anchor: Instant,
+    /// Step count up to which tracks have been scheduled.
+    scheduled: usize,
+    /// Events due at a position, in order.
+    pending: Vec<(f64, Event)>,
+    pub patterns: Vec<Pattern>,
+    /// Pattern indices and how many times each plays in a row, looping.
+    /// Empty keeps repeating the playing pattern.
+    pub song: Vec<(usize, usize)>,
+    place: Place,
+    /// Pattern to switch to at the next bar line.
+    queued: Option<usize>,
+    pub source: ClockSource,
+    running: bool,
+    pulse: u32,                                      // MIDI clocks since song position 0
+    clock_out: Option<(Box<dyn Write + Send>, u64)>, // sink, next pulse
+    tx: mpsc::Sender<Event>,
+}
+
+impl Sequencer {
+    pub fn new(bpm: f64, beats: u8, sub_beats: u8, tx: mpsc::Sender<Event>) -> Self {
+        Self {
+            bpm,
+            beats,
+            sub_beats,
+            total_beats: (beats * sub_beats) as usize,
+            swing: 0.0,
+            position: 0.0,
+            anchor: Instant::now(),
+            scheduled: 0,
+            pending: Vec::new(),
+            patterns: vec![Pattern::new("A", 1)],
+            song: Vec::new(),
+            place: Place::default(),
+            queued: None,
+            source: ClockSource::Internal,
+            running: false,
+            pulse: 0,
+            clock_out: None,
+            tx,
+        }
+    }
+
+    /// Send MIDI clock to `out` while running on the internal clock, starting
+    /// with [Message::Start].
+    pub fn clock_out(&mut self, mut out: impl Write + Send + 'static) {
+        let mut bytes = Vec::new();
+        Message::Start.encode(&mut bytes);
+        _ = out.write_all(&bytes);
+        _ = out.flush();
+        // Pulses fall on the playhead's steps, from the next one due
+        let next = (self.position() * self.pulses_per_step()).ceil() as u64;
+        self.clock_out = Some((Box::new(out), next));
+    }
+
+    pub fn bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Change tempo from the current playhead on, without moving it.
+    pub fn set_bpm(&mut self, bpm: f64) {
+        self.position = self.position();
+        self.anchor = Instant::now();
+        self.bpm = bpm;
+    }
+
+    /// Playhead in steps since the start.
+    pub fn position(&self) -> f64 {
+        match self.source {
+            ClockSource::Internal => {
+                self.position + self.anchor.elapsed().as_secs_f64() / self.step_secs()
+            }
+            ClockSource::Midi => self.position,
+        }
+    }
+
+    fn step_secs(&self) -> f64 {
+        60.0 / self.bpm / self.sub_beats as f64
+    }
+
+    fn pulses_per_step(&self) -> f64 {
+        PPQN as f64 / self.sub_beats as f64
+    }
+
+    pub fn update(&mut self) {
+        if self.source == ClockSource::Midi {
+            return;
+        }
+
+        let due = (self.position() * self.pulses_per_step()).floor() as u64;
+        if let Some((out, next)) = &mut self.clock_out {
+            let mut bytes = Vec::new();
+            while *next <= due {
+                Message::Clock.encode(&mut bytes);
+                *next += 1;
+            }
+            if !bytes.is_empty() && (out.write_all(&bytes).is_err() || out.flush().is_err()) {
+                self.clock_out = None;
+            }
+        }
+
+        self.advance(self.position());
+    }
+
+    /// Follow an incoming MIDI clock or transport message, see [ClockSource::Midi].
+    pub fn clock(&mut self, msg: Message) {
+        let per_step = self.pulses_per_step();
+        match msg {
+            Message::Start => {
+                self.locate(0);
+                self.running = true;
+            }
+            Message::Continue => self.running = true,
+            Message::Stop => {
+                self.running = false;
+                self.release();
+            }
+            Message::SongPosition(sixteenths) => self.locate(sixteenths as u32 * PPQN / 4),
+            Message::Clock if self.running => {
+                self.position = self.pulse as f64 / per_step;
+                self.advance(self.position);
+                self.pulse += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Jump to MIDI clock `pulse`, dropping what was scheduled.
+    fn locate(&mut self, pulse: u32) {
+        self.release();
+        self.pulse = pulse;
+        self.position = pulse as f64 / self.pulses_per_step();
+        self.scheduled = self.position.ceil() as usize;
+
+        self.place = Place {
+            pattern: self.place.pattern,
+            ..Default::default()
+        };
+        self.queued = None;
+        // Only find the place again, the tracks keep their current variations
+        let (mut place, mut queued) = (self.place, self.queued);
+        for step in 0..self.scheduled {
+            self.enter(&mut place, &mut queued, step);
+        }
+        (self.place, self.queued) = (place, queued);
+    }
+
+    /// Send the pending note offs now and forget the rest.
+    fn release(&mut self) {
+        for (_, event) in self.pending.drain(..) {
+            if let Event::NoteOff(..) = event {
+                _ = self.tx.send(event);
+            }
+        }
+    }
+
+    /// Schedule the tracks ahead of `position` and send what is due.
+    fn advance(&mut self, position: f64) {
+        let mut pending = std::mem::take(&mut self.pending);
+        while (self.scheduled as f64) <= position + LOOKAHEAD {
+            self.enter_live(self.scheduled);
+            self.events(self.place, self.scheduled, &mut pending);
+            self.scheduled += 1;
+        }
+        self.pending = pending;
+        self.pending
+            .sort_by(|a, b| a.0.total_cmp(&b.0).then(order(a).cmp(&order(b))));
+
+        let due = self.pending.partition_point(|&(time, _)| time <= position);
+        for (_, event) in self.pending.drain(..due) {
+            // print!(" *");
+            _ = self.tx.send(event);
+        }
+    }
+
+    /// Sequencer steps `pattern` lasts.
+    fn pattern_steps(&self, pattern: usize) -> usize {
+        let bars = self.patterns.get(pattern).map_or(1, |p| p.bars);
+        (bars * self.total_beats).max(1)
+    }
+
+    /// Move `place` on to sequencer step `step`, switching patterns at the
+    /// end of one or, when one is `queued`, at a bar line.
+    fn enter(&self, place: &mut Place, queued: &mut Option<usize>, step: usize) {
+        if step == 0 {
+            // The song may have been arranged since `place` was made
+            place.pattern = self.song.first().map_or(place.pattern, |&(p, _)| p);
+        }
+        if step - place.start >= self.pattern_steps(place.pattern) {
+            place.start = step;
+            if !self.song.is_empty() {
+                // A detour doesn't count as a repeat of the song's entry
+                if !place.detour {
+                    let (_, repeats) = self.song[place.entry % self.song.len()];
+                    place.repeat += 1;
+                    if place.repeat >= repeats {
+                        place.entry = (place.entry + 1) % self.song.len();
+                        place.repeat = 0;
+                    }
+                }
+                place.pattern = self.song[place.entry % self.song.len()].0;
+            }
+            place.detour = false;
+        }
+        if let Some(next) = *queued
+            && (step - place.start).is_multiple_of(self.total_beats.max(1))
+        {
+            *queued = None;
+            place.pattern = next;
+            place.start = step;
+            place.detour = true;
+        }
+    }
+
+    /// [Sequencer::enter] for the live playhead, mutating tracks on bar lines.
+    fn enter_live(&mut self, step: usize) {
+        let (mut place, mut queued) = (self.place, self.queued);
+        self.enter(&mut place, &mut queued, step);
+        (self.place, self.queued) = (place, queued);
+
+        let bar = self.total_beats.max(1);
+        if step == 0 || !step.is_multiple_of(bar) {
+            return;
+        }
+        for track in self.channels_mut().into_iter().flatten() {
+            if let Some(mutation) = &mut track.mutation
+                && (step / bar).is_multiple_of(mutation.every.max(1))
+            {
+                mutation.vary(track.steps.len());
+            }
+        }
+    }
+
+    /// Push the events of sequencer step `step` at `place`, timed in steps.
+    fn events(&self, place: Place, step: usize, out: &mut Vec<(f64, Event)>) {
+        let Some(pattern) = self.patterns.get(place.pattern) else {
+            return;
+        };
+        let from = out.len();
+        for track in &pattern.channels {
+            for index in track.steps_in(step - place.start) {
+                track.events(index, self.swing, out);
+            }
+        }
+        for (time, _) in &mut out[from..] {
+            *time += place.start as f64;
+        }
+    }
+
+    /// Index of the pattern playing.
+    pub fn playing(&self) -> usize {
+        self.place.pattern
+    }
+
+    pub fn queued(&self) -> Option<usize> {
+        self.queued
+    }
+
+    /// Switch to `pattern` at the next bar line, the song carries on after it.
+    pub fn queue(&mut self, pattern: usize) {
+        if pattern < self.patterns.len() {
+            self.queued = Some(pattern);
+        }
+    }
+
+    /// Playhead in steps since the playing pattern started.
+    pub fn pattern_position(&self) -> f64 {
+        (self.position() - self.place.start as f64).max(0.0)
+    }
+
+    /// Add an empty pattern, returns its index.
+    pub fn add_pattern(&mut self, name: &str, bars: usize) -> usize {
+        self.patterns.push(Pattern::new(name, bars));
+        self.patterns.len() - 1
+    }
+
+    /// Set [Sequencer::song] from pattern names, each optionally followed by
+    /// `*` and a repeat count, like `intro verse*4 chorus*2`.
+    pub fn arrange(&mut self, song: &str) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        self.song = song
+            .split_whitespace()
+            .map(|entry| {
+                let (name, repeats) = entry.split_once('*').unwrap_or((entry, "1"));
+                let pattern = self
+                    .patterns
+                    .iter()
+                    .position(|p| p.name == name)
+                    .ok_or_else(|| invalid(format!("unknown pattern {name}")))?;
+                let repeats = repeats
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n > 0)
+                    .ok_or_else(|| invalid(format!("invalid repeat count in {entry}")))?;
+                Ok((pattern, repeats))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(())
+    }
+
+    /// Bars in one pass of the song.
+    pub fn song_bars(&self) -> usize {
+        self.song
+            .iter()
+            .map(|&(p, n)| self.patterns.get(p).map_or(1, |p| p.bars) * n)
+            .sum()
+    }
+
+    /// Tracks of the playing pattern.
+    pub fn channels(&self) -> &[Track] {
+        self.patterns
+            .get(self.place.pattern)
+            .map_or(&[], |p| &p.channels)
+    }
+
+    /// Tracks of the playing pattern, `None` if [Sequencer::patterns] lacks it.
+    pub fn channels_mut(&mut self) -> Option<&mut Vec<Track>> {
+        self.patterns
+            .get_mut(self.place.pattern)
+            .map(|p| &mut p.channels)
+    }
+
+    /// Add a track playing `pat` to the playing pattern, see [Track::parse].
+    pub fn add_channel(&mut self, inst_id: usize, pat: &str) {
+        if let Some(channels) = self.channels_mut() {
+            channels.push(Track::parse(inst_id, pat));
+        }
+    }
+
+    /// Flip `step` of channel `index` between a default hit and a rest.
+    pub fn toggle_step(&mut self, index: usize, step: usize) {
+        if let Some(slot) = self
+            .channels_mut()
+            .and_then(|channels| channels.get_mut(index))
+            .and_then(|track| track.steps.get_mut(step))
+        {
+            *slot = match slot {
+                Some(_) => None,
+                None => Some(Step::default()),
+            };
+        }
+    }
+
+    pub fn remove_channel(&mut self, index: usize) {
+        if let Some(channels) = self.channels_mut()
+            && index < channels.len()
+        {
+            channels.remove(index);
+        }
+    }
+
+    /// Export `bars` bars played from the start of the song as a Standard
+    /// MIDI File, a track per instrument on its channel in `map`. Triggers
+    /// become one step long [smf::TRIGGER_NOTE]s, probabilities are rolled
+    /// once and tracks play their current variation.
+    pub fn export(&self, bars: usize, map: &ChannelMap) -> Writer {
+        let mut writer = Writer::new(self.bpm, self.beats);
+        let step = smf::PPQ as f64 / self.sub_beats as f64;
+        let tick = |time: f64| (time.max(0.0) * step).round() as u64;
+
+        let mut timed = Vec::new();
+        let mut place = Place {
+            pattern: self.place.pattern,
+            ..Default::default()
+        };
+        for step in 0..bars * self.total_beats {
+            self.enter(&mut place, &mut None, step);
+            self.events(place, step, &mut timed);
+        }
+
+        let mut instruments: BTreeMap<usize, Vec<(f64, Event)>> = BTreeMap::new();
+        for (time, event) in timed {
+            instruments
+                .entry(event.inst())
+                .or_default()
+                .push((time, event));
+        }
+
+        for (inst, timed) in instruments {
+            let mut events = Vec::new();
+            for (time, event) in timed {
+                let (on, off) = match event {
+                    Event::Trigger(inst, vel) => (
+                        Event::NoteOn(inst, smf::TRIGGER_NOTE, vel),
+                        Some((time + 1.0, Event::NoteOff(inst, smf::TRIGGER_NOTE))),
+                    ),
+                    event => (event, None),
+                };
+                events.extend(map.message(on).map(|msg| (tick(time), msg)));
+                if let Some((time, off)) = off {
+                    events.extend(map.message(off).map(|msg| (tick(time), msg)));
+                }
+            }
+            writer.track(&format!("instrument {inst}"), events);
+        }
+
+        writer
+    }
+}
+
+impl Drop for Sequencer {
+    fn drop(&mut self) {
+        self.release();
+        if let Some((out, _)) = &mut self.clock_out {
+            let mut bytes = Vec::new();
+            Message::Stop.encode(&mut bytes);
+            _ = out.write_all(&bytes);
+            _ = out.flush();
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::midi::{self, ChannelMap, Message, Parser};
+use crate::{Event, Synth, invalid};
+
+/// Default tempo until the file sets one, 120 bpm.
+const DEFAULT_TEMPO: u32 = 500_000; // micros per quarter note
+
+/// Ticks per quarter note of exported files.
+pub const PPQ: u16 = 480;
+
+/// Note written for [Event::Trigger]s, which have none.
+pub const TRIGGER_NOTE: u8 = 60;
+
+/// A Standard MIDI File flattened into one time-ordered list.
+pub struct Smf {
+    /// 0: single track, 1: parallel tracks
+    pub format: u16,
+    pub tracks: usize,
+    /// Events with their time from the start in secs, tempo map applied.
+    pub events: Vec<(f64, Message)>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let chunk = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("unexpected end of file"))?;
+        self.pos += n;
+        Ok(chunk)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Variable length quantity, 7 bits per byte, high bit set means more.
+    fn vlq(&mut self) -> io::Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = value << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("variable length quantity too long"))
+    }
+
+    fn chunk(&mut self, id: &[u8; 4]) -> io::Result<Reader<'a>> {
+        if self.take(4)? != id {
+            return Err(invalid("unexpected chunk"));
+        }
+        let len = self.u32()? as usize;
+        Ok(Reader {
+            bytes: self.take(len)?,
+            pos: 0,
+        })
+    }
+}
+
+enum Item {
+    Tempo(u32),
+    Msg(Message),
+}
+
+/// Read every event of a track as (absolute tick, item).
+fn read_track(mut track: Reader, items: &mut Vec<(u64, Item)>) -> io::Result<()> {
+    let mut parser = Parser::new();
+    let mut running = None;
+    let mut tick = 0u64;
+
+    while track.pos < track.bytes.len() {
+        tick += track.vlq()? as u64;
+
+        let byte = track.u8()?;
+        match byte {
+            // Meta and sysex events cancel running status
+            0xFF => {
+                running = None;
+                let kind = track.u8()?;
+                let len = track.vlq()? as usize;
+                let data = track.take(len)?;
+                match (kind, data) {
+                    (0x2F, _) => break,
+                    (0x51, &[a, b, c]) => {
+                        items.push((tick, Item::Tempo(u32::from_be_bytes([0, a, b, c]))))
+                    }
+                    _ => {}
+                }
+            }
+            0xF0 | 0xF7 => {
+                running = None;
+                let len = track.vlq()? as usize;
+                track.take(len)?;
+            }
+            _ => {
+                let status = if byte & 0x80 != 0 {
+                    running = Some(byte);
+                    byte
+                } else {
+                    track.pos -= 1;
+                    running.ok_or_else(|| invalid("data byte without status"))?
+                };
+
+                let mut msg = parser.push(status);
+                for &data in track.take(midi::data_len(status))? {
+                    msg = parser.push(data);
+                }
+                if let Some(msg) = msg {
+                    items.push((tick, Item::Msg(msg)));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Smf {
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let mut file = Reader { bytes, pos: 0 };
+
+        let mut header = file.chunk(b"MThd")?;
+        let format = header.u16()?;
+        let tracks = header.u16()? as usize;
+        let division = header.u16()?;
+
+        if format > 1 {
+            return Err(invalid("only format 0 and 1 are supported"));
+        }
+        let valid_division = match division & 0x8000 {
+            0 => division > 0,
+            _ => {
+                matches!((division >> 8) as u8 as i8, -24 | -25 | -29 | -30) && division & 0xFF > 0
+            }
+        };
+        if !valid_division {
+            return Err(invalid("invalid time division"));
+        }
+
+        let mut items = Vec::new();
+        for _ in 0..tracks {
+            // Skip unknown chunks between tracks
+            while file.bytes.get(file.pos..file.pos + 4) != Some(b"MTrk") {
+                if file.pos >= file.bytes.len() {
+                    return Err(invalid("missing track"));
+                }
+                file.take(4)?;
+                let len = file.u32()? as usize;
+                file.take(len)?;
+            }
+            read_track(file.chunk(b"MTrk")?, &mut items)?;
+        }
+
+        // Stable, so same tick events keep their track order
+        items.sort_by_key(|&(tick, _)| tick);
+
+        // Ticks are relative to the tempo unless the division is SMPTE
+        let secs_per_tick = |tempo: u32| {
+            if division & 0x8000 != 0 {
+                let fps = -((division >> 8) as i8) as f64;
+                let ticks_per_frame = (division & 0xFF) as f64;
+                1.0 / (fps * ticks_per_frame)
+            } else {
+                tempo as f64 / 1e6 / division as f64
+            }
+        };
+
+        let mut events = Vec::with_capacity(items.len());
+        let (mut tempo, mut last_tick, mut secs) = (DEFAULT_TEMPO, 0, 0.0);
+        for (tick, item) in items {
+            secs += (tick - last_tick) as f64 * secs_per_tick(tempo);
+            last_tick = tick;
+            match item {
+                Item::Tempo(t) => tempo = t,
+                Item::Msg(msg) => events.push((secs, msg)),
+            }
+        }
+
+        Ok(Self {
+            format,
+            tracks,
+            events,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Length in secs, up to the last event.
+    pub fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |&(secs, _)| secs)
+    }
+
+    /// Play in real time on a background thread, sending [Event]s to `tx`.
+    pub fn play(self, map: ChannelMap, tx: mpsc::Sender<Event>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let start = Instant::now();
+            for (secs, msg) in self.events {
+                let at = start + Duration::from_secs_f64(secs);
+                if let Some(wait) = at.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+                if let Some(event) = map.event(msg)
+                    && tx.send(event).is_err()
+                {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Render offline through `synth`, with `tail` secs after the last
+    /// event for releases to ring out.
+    pub fn render<const N: usize>(
+        &self,
+        synth: &mut Synth<N>,
+        map: &ChannelMap,
+        tail: f64,
+    ) -> Vec<f32> {
+        const BLOCK: usize = 64;
+
+        let sr = synth.sample_rate();
+        let len = ((self.duration() + tail) * sr) as usize;
+        let mut out = vec![0.0; len];
+        let mut events = self.events.iter().peekable();
+
+        for (i, block) in out.chunks_mut(BLOCK).enumerate() {
+            let end = ((i + 1) * BLOCK) as f64 / sr;
+            while let Some((_, msg)) = events.next_if(|&&(secs, _)| secs < end) {
+                if let Some(event) = map.event(*msg) {
+                    synth.handle(event);
+                }
+            }
+            synth.process(block);
+        }
+
+        out
+    }
+}
+
+fn write_vlq(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = [0u8; 4];
+    let mut i = 3;
+    bytes[i] = (value & 0x7F) as u8;
+    value >>= 7;
+    while value > 0 {
+        i -= 1;
+        bytes[i] = (value & 0x7F) as u8 | 0x80;
+        value >>= 7;
+    }
+    out.extend(&bytes[i..]);
+}
+
+fn write_meta(out: &mut Vec<u8>, delta: u32, kind: u8, data: &[u8]) {
+    write_vlq(out, delta);
+    out.extend([0xFF, kind]);
+    write_vlq(out, data.len() as u32);
+    out.extend(data);
+}
+
+/// Builds a format 1 Standard MIDI File, a tempo track followed by one
+/// track per [Writer::track], at [PPQ] ticks per quarter note.
+pub struct Writer {
+    bpm: f64,
+    /// Quarter notes per bar
+    beats: u8,
+    tracks: Vec<(String, Vec<(u64, Message)>)>,
+}
+
+impl Writer {
+    pub fn new(bpm: f64, beats: u8) -> Self {
+        Self {
+            bpm,
+            beats,
+            tracks: Vec::new(),
+        }
+    }
+
+    /// Ticks at `secs` from the start, at the writer's tempo.
+    pub fn ticks(&self, secs: f64) -> u64 {
+        (secs * self.bpm / 60.0 * PPQ as f64).round() as u64
+    }
+
+    /// Add a track of (tick, message) events, in any order.
+    pub fn track(&mut self, name: &str, mut events: Vec<(u64, Message)>) {
+        // Note offs first so back to back notes don't cut each other
+        events.sort_by_key(|&(tick, msg)| (tick, matches!(msg, Message::NoteOn { .. })));
+        self.tracks.push((name.to_string(), events));
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend(b"MThd");
+        out.extend(6u32.to_be_bytes());
+        out.extend(1u16.to_be_bytes());
+        out.extend((self.tracks.len() as u16 + 1).to_be_bytes());
+        out.extend(PPQ.to_be_bytes());
+
+        let tempo = (60e6 / self.bpm).round() as u32;
+        let mut conductor = Vec::new();
+        write_meta(&mut conductor, 0, 0x51, &tempo.to_be_bytes()[1..]);
+        // beats/4, 24 clocks per click, 8 32nds per quarter
+        write_meta(&mut conductor, 0, 0x58, &[self.beats, 2, 24, 8]);
+        write_meta(&mut conductor, 0, 0x2F, &[]);
+        write_chunk(&mut out, &conductor);
+
+        for (name, events) in &self.tracks {
+            let mut track = Vec::new();
+            write_meta(&mut track, 0, 0x03, name.as_bytes());
+
+            let mut last = 0;
+            for &(tick, msg) in events {
+                write_vlq(&mut track, (tick - last) as u32);
+                msg.encode(&mut track);
+                last = tick;
+            }
+            write_meta(&mut track, 0, 0x2F, &[]);
+            write_chunk(&mut out, &track);
+        }
+
+        out
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::File::create(path)?.write_all(&self.to_bytes())
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, track: &[u8]) {
+    out.extend(b"MTrk");
+    out.extend((track.len() as u32).to_be_bytes());
+    out.extend(track);
+}
+
+/// Timestamps live [Event]s, to save a performance with [Writer].
+pub struct Recorder {
+    start: Instant,
+    events: Vec<(f64, Event)>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, event: Event) {
+        self.events
+            .push((self.start.elapsed().as_secs_f64(), event));
+    }
+
+    /// Add the recorded events to `writer` as one track.
+    pub fn write(&self, writer: &mut Writer, name: &str, map: &ChannelMap) {
+        let events = self
+            .events
+            .iter()
+            .filter_map(|&(secs, event)| Some((writer.ticks(secs), map.message(event)?)))
+            .collect();
+        writer.track(name, events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> Message {
+        Message::NoteOn {
+            channel: 0,
+            note,
+            velocity: 100,
+        }
+    }
+
+    fn note_off(note: u8) -> Message {
+        Message::NoteOff {
+            channel: 0,
+            note,
+            velocity: 0,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = Writer::new(120.0, 4);
+        let quarter = PPQ as u64;
+        writer.track("lead", vec![(quarter, note_off(60)), (0, note_on(60))]);
+        let smf = Smf::parse(&writer.to_bytes()).unwrap();
+
+        assert_eq!((smf.format, smf.tracks), (1, 2));
+        assert_eq!(smf.events, [(0.0, note_on(60)), (0.5, note_off(60))]);
+    }
+
+    #[test]
+    fn tempo_change() {
+        let quarter = PPQ as u32;
+        let mut conductor = Vec::new();
+        write_meta(&mut conductor, 0, 0x51, &500_000u32.to_be_bytes()[1..]);
+        write_meta(
+            &mut conductor,
+            quarter,
+            0x51,
+            &250_000u32.to_be_bytes()[1..],
+        );
+        write_meta(&mut conductor, 0, 0x2F, &[]);
+
+        let mut track = Vec::new();
+        for delta in [0, quarter, quarter] {
+            write_vlq(&mut track, delta);
+            note_on(60).encode(&mut track);
+        }
+        write_meta(&mut track, 0, 0x2F, &[]);
+
+        let mut bytes = Vec::new();
+        bytes.extend(b"MThd");
+        bytes.extend(6u32.to_be_bytes());
+        bytes.extend([0, 1, 0, 2]);
+        bytes.extend(PPQ.to_be_bytes());
+        write_chunk(&mut bytes, &conductor);
+        write_chunk(&mut bytes, &track);
+
+        let smf = Smf::parse(&bytes).unwrap();
+        let times: Vec<f64> = smf.events.iter().map(|&(secs, _)| secs).collect();
+        assert_eq!(times, [0.0, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn zero_division() {
+        let mut bytes = Writer::new(120.0, 4).to_bytes();
+        // Division follows the chunk header, format and track count
+        bytes[12..14].copy_from_slice(&[0, 0]);
+        assert!(Smf::parse(&bytes).is_err());
+    }
+}
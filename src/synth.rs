@@ -0,0 +1,1031 @@
+use std::cmp;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+
+use crate::env::{self, Envelope};
+use crate::filter::Filter;
+use crate::limiter::Limiter;
+use crate::modulation::{Smooth, Source, Target};
+use crate::osc::Osc;
+use crate::preset::{self, Instrument, Param};
+use crate::scope::{Decimator, Scope};
+use crate::tuning::Tuning;
+use crate::{A4, Hz};
+
+#[derive(Default)]
+struct Voice {
+    sr: f64,
+    inst_id: usize,
+    /// Wether this voice is _currently_ producing sound
+    active: bool,
+    /// Wether the note is still held down (no `note_off` yet)
+    held: bool,
+    /// Midi note 0..128
+    note: u8,
+    /// Velocity level 0..1 after the instrument's curve
+    velocity: f64,
+    /// Polyphonic aftertouch or MPE pressure 0..1
+    pressure: Smooth,
+    /// MPE member channel this voice's note arrived on
+    channel: Option<u8>,
+    /// MPE per note bend -1..1
+    bend: Smooth,
+    /// MPE timbre (CC 74) 0..1
+    timbre: Smooth,
+    freq: Hz,
+    slide: Slide,
+    env: Envelope,
+    lfos: Vec<Osc>,
+    oscs: Vec<Osc>,
+    filter: Option<Filter>,
+    /// Gain ramping 1 -> 0 while a stolen voice fades out
+    fade: Option<f64>,
+    /// Note to start once the fade is done
+    pending: Option<Pending>,
+    /// When the voice was (re)triggered, higher is newer
+    age: u64,
+}
+
+/// A note waiting for a stolen voice to fade out.
+#[derive(Clone, Copy)]
+struct Pending {
+    inst: usize,
+    note: Option<u8>,
+    vel: u8,
+    channel: Option<u8>,
+}
+
+/// Last expression sent on an MPE member channel, picked up by the next note.
+#[derive(Default, Clone, Copy)]
+struct Member {
+    bend: f64,
+    pressure: f64,
+    timbre: f64,
+}
+
+/// Exponential pitch slide towards a target frequency.
+#[derive(Default)]
+struct Slide {
+    target: Hz,
+    ratio: f64,
+    remaining: u32,
+}
+
+impl Slide {
+    fn start(&mut self, from: Hz, to: Hz, samples: u32) {
+        self.target = to;
+        self.remaining = samples;
+        if samples > 0 {
+            self.ratio = (to.0 / from.0).powf(1.0 / samples as f64);
+        }
+    }
+
+    /// Advance the slide, returns the new frequency if it changed.
+    fn next(&mut self, freq: Hz) -> Option<Hz> {
+        match self.remaining {
+            0 => None,
+            1 => {
+                self.remaining = 0;
+                Some(self.target)
+            }
+            _ => {
+                self.remaining -= 1;
+                Some(Hz(freq.0 * self.ratio))
+            }
+        }
+    }
+}
+
+/// Frequency of `note` through the tuning of `instrument`, `None` if unmapped.
+fn note_freq(instrument: &Instrument, note: u8, a4: f64) -> Option<Hz> {
+    match &instrument.tuning {
+        Some(tuning) => tuning.freq(note, a4),
+        None => Some(Hz::from_midi(note as f64, a4)),
+    }
+}
+
+/// The envelope of `instrument` with its times scaled by velocity.
+fn velocity_env(instrument: &Instrument, level: f64) -> env::Kind {
+    instrument
+        .env
+        .scale_time(1.0 - instrument.velocity_time * level)
+}
+
+impl Voice {
+    /// Start playing `note` (pitched instruments) with A4 tuned to `a4` Hz.
+    fn start(&mut self, inst: usize, instrument: &Instrument, note: Option<u8>, vel: u8, a4: f64) {
+        self.inst_id = inst;
+        self.active = true;
+        self.held = true;
+        self.fade = None;
+        self.slide = Slide::default();
+        self.velocity = instrument.velocity.level(vel);
+        self.pressure.reset(0.0);
+
+        match instrument.kind {
+            preset::Kind::Pitched => {
+                self.note = note.unwrap();
+                self.freq = note_freq(instrument, self.note, a4).unwrap_or_default();
+            }
+            preset::Kind::Percussive(freq) => {
+                self.freq = freq;
+            }
+        };
+
+        self.env = Envelope::new(velocity_env(instrument, self.velocity));
+        self.filter = instrument.filter.map(|(_, res)| Filter::new(self.sr, res));
+
+        self.lfos.clear();
+        for &(waveform, freq, gain) in &instrument.lfos {
+            self.lfos
+                .push(Osc::new(waveform, freq.into(), self.sr, gain));
+        }
+
+        self.oscs.clear();
+        for &(waveform, gain) in &instrument.oscs {
+            self.oscs.push(Osc::new(waveform, self.freq, self.sr, gain));
+        }
+    }
+
+    /// Tie the voice to an MPE member channel, starting from its latest expression.
+    fn set_channel(&mut self, channel: Option<u8>, members: &[Member; 16]) {
+        let member = channel.map_or(Member::default(), |ch| members[ch as usize]);
+        self.channel = channel;
+        self.bend.reset(member.bend);
+        self.pressure.reset(member.pressure);
+        self.timbre.reset(member.timbre);
+    }
+
+    fn set_freq(&mut self, freq: Hz) {
+        self.freq = freq;
+        for osc in &mut self.oscs {
+            osc.set_freq(freq, self.sr);
+        }
+    }
+
+    /// Slide from the current frequency to `to` over `secs`.
+    fn glide_to(&mut self, to: Hz, secs: f64) {
+        let samples = (secs * self.sr) as u32;
+        if samples == 0 {
+            self.slide = Slide::default();
+            self.set_freq(to);
+        } else {
+            self.slide.start(self.freq, to, samples);
+        }
+    }
+
+    /// Current value of a modulation source, 0..1 (-1..1 for bends).
+    fn source(&self, source: Source, controls: &Controls) -> f64 {
+        match source {
+            Source::Velocity => self.velocity,
+            Source::PitchBend => controls.bend.value(),
+            Source::ModWheel => controls.mod_wheel.value(),
+            Source::Aftertouch => controls.pressure.value(),
+            Source::PolyPressure => self.pressure.value(),
+            Source::Timbre => self.timbre.value(),
+        }
+    }
+}
+
+/// Continuous controllers of an instrument, like a MIDI channel's.
+#[derive(Default, Clone, Copy)]
+struct Controls {
+    /// -1..1
+    bend: Smooth,
+    mod_wheel: Smooth,
+    pressure: Smooth,
+}
+
+/// Which voice to take over when none are free.
+#[derive(Clone, Copy, Default)]
+pub enum Steal {
+    Oldest,
+    #[default]
+    Quietest,
+}
+
+/// How [Synth] picks a voice for a new note.
+#[derive(Clone, Copy)]
+pub struct Policy {
+    pub steal: Steal,
+    /// Retrigger the voice already playing a note instead of stacking another
+    pub reuse_note: bool,
+    /// Steal released voices before held ones
+    pub releasing_first: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            steal: Steal::default(),
+            reuse_note: true,
+            releasing_first: true,
+        }
+    }
+}
+
+/// The scope keeps every this many output samples.
+const SCOPE_DECIMATION: usize = 2;
+
+/// Time (secs) a stolen voice takes to fade out before it is reused.
+const STEAL_FADE: f64 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// Instrument, note, velocity
+    NoteOn(usize, u8, u8),
+    NoteOff(usize, u8),
+    /// Instrument, velocity
+    Trigger(usize, u8),
+    /// Instrument, controller, value
+    ControlChange(usize, u8, u8),
+    /// Instrument, -8192..8192
+    PitchBend(usize, i16),
+    /// Instrument, channel pressure
+    Aftertouch(usize, u8),
+    /// Instrument, note, pressure
+    PolyAftertouch(usize, u8, u8),
+    /// Instrument, program
+    ProgramChange(usize, u8),
+    /// MPE note: instrument, member channel, note, velocity
+    MemberNoteOn(usize, u8, u8, u8),
+    /// Instrument, member channel, note
+    MemberNoteOff(usize, u8, u8),
+    /// MPE per note bend: instrument, member channel, -8192..8192
+    MemberBend(usize, u8, i16),
+    /// MPE per note pressure: instrument, member channel, pressure
+    MemberPressure(usize, u8, u8),
+    /// MPE per note timbre (CC 74): instrument, member channel, value
+    MemberTimbre(usize, u8, u8),
+    /// Edit an instrument
+    Param(usize, Param),
+    /// Instrument, index of a [Synth::add_tuning] tuning, `None` for 12-EDO
+    Tuning(usize, Option<usize>),
+}
+
+impl Event {
+    /// The instrument this event is for.
+    pub fn inst(&self) -> usize {
+        match *self {
+            Self::NoteOn(inst, ..)
+            | Self::NoteOff(inst, _)
+            | Self::Trigger(inst, _)
+            | Self::ControlChange(inst, ..)
+            | Self::PitchBend(inst, _)
+            | Self::Aftertouch(inst, _)
+            | Self::PolyAftertouch(inst, ..)
+            | Self::ProgramChange(inst, _)
+            | Self::MemberNoteOn(inst, ..)
+            | Self::MemberNoteOff(inst, ..)
+            | Self::MemberBend(inst, ..)
+            | Self::MemberPressure(inst, ..)
+            | Self::MemberTimbre(inst, ..)
+            | Self::Param(inst, _)
+            | Self::Tuning(inst, _) => inst,
+        }
+    }
+}
+
+/// Velocity for input without any, like the computer keyboard.
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+/// Fixed headroom on the mix, so a few voices fit before the master gain.
+const HEADROOM: f64 = 0.2;
+
+/// Levels of a signal over the last [Synth::process] block.
+#[derive(Default)]
+pub struct Meter {
+    peak: AtomicU32, // f32 bits
+    rms: AtomicU32,  // f32 bits
+    /// Samples over full scale so far.
+    pub clips: AtomicUsize,
+}
+
+impl Meter {
+    pub fn peak(&self) -> f32 {
+        f32::from_bits(self.peak.load(Ordering::Relaxed))
+    }
+
+    pub fn rms(&self) -> f32 {
+        f32::from_bits(self.rms.load(Ordering::Relaxed))
+    }
+
+    pub fn clips(&self) -> usize {
+        self.clips.load(Ordering::Relaxed)
+    }
+}
+
+/// Running peak, sum of squares and clips of a block, published to a [Meter].
+#[derive(Default, Clone, Copy)]
+struct Level {
+    peak: f64,
+    square: f64,
+    clips: usize,
+}
+
+impl Level {
+    fn add(&mut self, x: f64) {
+        self.peak = self.peak.max(x.abs());
+        self.square += x * x;
+        if x.abs() > 1.0 {
+            self.clips += 1;
+        }
+    }
+
+    fn publish(&mut self, meter: &Meter, len: usize) {
+        let rms = (self.square / len.max(1) as f64).sqrt();
+        meter
+            .peak
+            .store((self.peak as f32).to_bits(), Ordering::Relaxed);
+        meter.rms.store((rms as f32).to_bits(), Ordering::Relaxed);
+        meter.clips.fetch_add(self.clips, Ordering::Relaxed);
+        *self = Self::default();
+    }
+}
+
+/// Synth state published to other threads, like a UI.
+pub struct Status {
+    /// Voices the synth has.
+    pub capacity: usize,
+    /// Voices playing.
+    pub voices: AtomicUsize,
+    /// Bit per MIDI note held on a pitched instrument.
+    notes: [AtomicU64; 2],
+    /// Recent output.
+    pub scope: Scope,
+    /// Per instrument, before the master gain.
+    pub instruments: Box<[Meter]>,
+    /// After the master gain, clips counted before the limiter.
+    pub master: Meter,
+    gain: AtomicU32,     // f32 bits, master
+    limiting: AtomicU32, // f32 bits, limiter gain
+}
+
+impl Status {
+    /// Master gain, linear.
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Gain the limiter applies, 1.0 when not limiting.
+    pub fn limiting(&self) -> f32 {
+        f32::from_bits(self.limiting.load(Ordering::Relaxed))
+    }
+
+    pub fn is_held(&self, note: u8) -> bool {
+        let note = note.min(127) as usize;
+        self.notes[note / 64].load(Ordering::Relaxed) & (1 << (note % 64)) != 0
+    }
+}
+
+pub struct Synth<const N: usize = 64> {
+    sr: f64,
+    voices: [Voice; N],
+    instruments: Vec<Instrument>,
+    /// Notes held down per instrument, oldest first, for mono note priority
+    held: Vec<Vec<(u8, u8)>>,
+    /// Last note frequency per instrument, where portamento slides from
+    last_freq: Vec<Option<Hz>>,
+    controls: Vec<Controls>,
+    members: [Member; 16],
+    pub policy: Policy,
+    /// Reference pitch of A4 in Hz, for tunings without their own
+    pub a4: f64,
+    /// Note counter, for [Voice::age]
+    age: u64,
+    /// Master bus brickwall, on after the master gain.
+    pub limiter: Limiter,
+    /// Levels this block per instrument, the master last
+    levels: Vec<Level>,
+    /// Mix of each instrument for the current sample
+    inst_mix: Vec<f64>,
+    /// Tunings [Event::Tuning] picks from, instruments' own ones too so
+    /// switching away never frees on the audio thread.
+    tunings: Vec<Arc<Tuning>>,
+    /// Output on its way to [Status::scope].
+    decimator: Decimator,
+    status: Arc<Status>,
+    rx: mpsc::Receiver<Event>,
+}
+
+impl<const N: usize> Synth<N> {
+    pub fn new(sr: f64, rx: mpsc::Receiver<Event>, instruments: Vec<Instrument>) -> Self {
+        let meters = instruments.iter().map(|_| Meter::default()).collect();
+        Self {
+            sr,
+            voices: std::array::from_fn(|_| Voice {
+                sr,
+                oscs: Vec::with_capacity(5),
+                lfos: Vec::with_capacity(5),
+                ..Default::default()
+            }),
+            held: instruments.iter().map(|_| Vec::with_capacity(16)).collect(),
+            levels: vec![Level::default(); instruments.len() + 1],
+            inst_mix: vec![0.0; instruments.len()],
+            tunings: instruments
+                .iter()
+                .filter_map(|i| i.tuning.clone())
+                .collect(),
+            last_freq: vec![None; instruments.len()],
+            controls: vec![Controls::default(); instruments.len()],
+            members: [Member::default(); 16],
+            instruments,
+            policy: Policy::default(),
+            a4: A4,
+            age: 0,
+            limiter: Limiter::new(sr, 0.0015, 0.05, 0.98),
+            decimator: Decimator::new(SCOPE_DECIMATION, sr),
+            status: Arc::new(Status {
+                capacity: N,
+                voices: AtomicUsize::new(0),
+                notes: Default::default(),
+                scope: Scope::new(4096, sr / SCOPE_DECIMATION as f64),
+                instruments: meters,
+                master: Meter::default(),
+                gain: AtomicU32::new(1f32.to_bits()),
+                limiting: AtomicU32::new(1f32.to_bits()),
+            }),
+            rx,
+        }
+    }
+
+    /// Voices in use (or about to be) by `inst`.
+    fn voice_count(&self, inst: usize) -> usize {
+        self.voices
+            .iter()
+            .filter(|v| {
+                (v.active && v.fade.is_none() && v.inst_id == inst)
+                    || v.pending.is_some_and(|p| p.inst == inst)
+            })
+            .count()
+    }
+
+    fn find_voice_slot(&self, inst: usize, note: Option<u8>, channel: Option<u8>) -> Option<usize> {
+        let policy = self.policy;
+
+        // Retrigger the voice already playing this note
+        if policy.reuse_note
+            && let Some(idx) = self.voices.iter().position(|v| {
+                v.active
+                    && v.fade.is_none()
+                    && v.inst_id == inst
+                    && v.channel == channel
+                    && note.is_none_or(|n| v.note == n)
+            })
+        {
+            return Some(idx);
+        }
+
+        let at_limit = self.instruments[inst]
+            .max_voices
+            .is_some_and(|max| self.voice_count(inst) >= max);
+
+        // Try to find a free voice first, unless it is reserved by another instrument
+        if !at_limit {
+            let unmet: usize = self
+                .instruments
+                .iter()
+                .enumerate()
+                .filter(|&(i, instrument)| i != inst && instrument.reserve > 0)
+                .map(|(i, instrument)| instrument.reserve.saturating_sub(self.voice_count(i)))
+                .sum();
+            let free = self.voices.iter().filter(|v| !v.active).count();
+
+            if free > unmet {
+                return self.voices.iter().position(|v| !v.active);
+            }
+        }
+
+        // Free voices left are reserved, so steal from this instrument or one
+        // above its reservation, preferring voices that aren't already fading
+        // out for another note
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| {
+                v.active
+                    && (v.inst_id == inst
+                        || !at_limit
+                            && self.voice_count(v.inst_id) > self.instruments[v.inst_id].reserve)
+            })
+            .min_by(|(_, a), (_, b)| {
+                a.fade
+                    .is_some()
+                    .cmp(&b.fade.is_some())
+                    .then_with(|| match policy.releasing_first {
+                        true => a.held.cmp(&b.held),
+                        false => cmp::Ordering::Equal,
+                    })
+                    .then_with(|| match policy.steal {
+                        Steal::Oldest => a.age.cmp(&b.age),
+                        Steal::Quietest => a
+                            .env
+                            .amp()
+                            .partial_cmp(&b.env.amp())
+                            .unwrap_or(cmp::Ordering::Equal),
+                    })
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    /// Fade out voices of other instruments in the same choke group.
+    fn choke(&mut self, inst: usize) {
+        let Some(group) = self.instruments[inst].choke else {
+            return;
+        };
+
+        for v in self.voices.iter_mut().filter(|v| {
+            v.active
+                && v.inst_id != inst
+                && v.pending.is_none()
+                && self.instruments[v.inst_id].choke == Some(group)
+        }) {
+            v.held = false;
+            v.fade = Some(v.fade.unwrap_or(1.0));
+        }
+    }
+
+    fn init_voice(&mut self, inst: usize, note: Option<u8>, vel: u8, channel: Option<u8>) {
+        let instrument = &self.instruments[inst];
+        // Pitched instruments ignore triggers, and keys left out of the
+        // tuning's keyboard mapping are silent
+        if instrument.kind == preset::Kind::Pitched
+            && note.is_none_or(|note| note_freq(instrument, note, self.a4).is_none())
+        {
+            return;
+        }
+
+        self.choke(inst);
+
+        let instrument = &self.instruments[inst];
+        let Some(index) = self.find_voice_slot(inst, note, channel) else {
+            return;
+        };
+        let voice = &mut self.voices[index];
+
+        self.age += 1;
+        voice.age = self.age;
+
+        if !voice.active {
+            voice.start(inst, instrument, note, vel, self.a4);
+            voice.set_channel(channel, &self.members);
+        } else if voice.inst_id == inst
+            && voice.channel == channel
+            && note.is_none_or(|n| voice.note == n)
+        {
+            voice.held = true;
+            voice.velocity = instrument.velocity.level(vel);
+            voice
+                .env
+                .retrigger(velocity_env(instrument, voice.velocity));
+        } else {
+            voice.held = false;
+            voice.fade = Some(voice.fade.unwrap_or(1.0));
+            voice.pending = Some(Pending {
+                inst,
+                note,
+                vel,
+                channel,
+            });
+            return;
+        }
+
+        if note.is_some() && instrument.kind == preset::Kind::Pitched {
+            let to = voice.freq;
+            if let Some(from) = self.last_freq[inst] {
+                voice.set_freq(from);
+                voice.glide_to(to, instrument.glide.time(from, to));
+            }
+            self.last_freq[inst] = Some(to);
+        }
+    }
+
+    fn note_on(&mut self, inst: usize, note: u8, vel: u8) {
+        if self.instruments[inst].mode == preset::Mode::Poly {
+            self.init_voice(inst, Some(note), vel, None);
+            return;
+        }
+
+        let held = &mut self.held[inst];
+        held.retain(|&(n, _)| n != note);
+        held.push((note, vel));
+
+        // A note with higher priority keeps sounding
+        if self.instruments[inst].priority.pick(held) == Some((note, vel)) {
+            self.mono_play(inst, note, vel);
+        }
+    }
+
+    /// Move the single voice of a mono instrument to `note`.
+    fn mono_play(&mut self, inst: usize, note: u8, vel: u8) {
+        let instrument = &self.instruments[inst];
+
+        let Some(voice) = self
+            .voices
+            .iter_mut()
+            .find(|v| v.active && v.fade.is_none() && v.inst_id == inst)
+        else {
+            self.init_voice(inst, Some(note), vel, None);
+            return;
+        };
+
+        let Some(to) = note_freq(instrument, note, self.a4) else {
+            return;
+        };
+        voice.glide_to(to, instrument.glide.time(voice.freq, to));
+        voice.note = note;
+
+        if !(instrument.mode == preset::Mode::Legato && voice.held) {
+            voice.velocity = instrument.velocity.level(vel);
+            voice
+                .env
+                .retrigger(velocity_env(instrument, voice.velocity));
+        }
+
+        voice.held = true;
+        self.last_freq[inst] = Some(to);
+    }
+
+    fn note_off(&mut self, inst: usize, note: u8) {
+        if self.instruments[inst].mode != preset::Mode::Poly {
+            let held = &mut self.held[inst];
+            held.retain(|&(n, _)| n != note);
+
+            // Fall back to the next held note instead of releasing
+            if let Some((next, vel)) = self.instruments[inst].priority.pick(held) {
+                if self
+                    .voices
+                    .iter()
+                    .any(|v| v.active && v.inst_id == inst && v.note == note)
+                {
+                    self.mono_play(inst, next, vel);
+                }
+                return;
+            }
+        }
+
+        for v in self
+            .voices
+            .iter_mut()
+            .filter(|v| v.active && v.inst_id == inst && v.note == note)
+        {
+            v.held = false;
+            v.env.note_off();
+        }
+
+        // Released before the stolen voice finished fading, drop it
+        for v in self.voices.iter_mut().filter(|v| {
+            v.pending
+                .is_some_and(|p| p.inst == inst && p.note == Some(note))
+        }) {
+            v.pending = None;
+        }
+    }
+
+    fn control_change(&mut self, inst: usize, control: u8, value: u8) {
+        match control {
+            // Mod Wheel
+            1 => self.controls[inst].mod_wheel.target = value as f64 / 127.0,
+            // All Sound Off
+            120 => {
+                for v in self
+                    .voices
+                    .iter_mut()
+                    .filter(|v| v.active && v.inst_id == inst)
+                {
+                    v.held = false;
+                    v.pending = None;
+                    v.fade = Some(v.fade.unwrap_or(1.0));
+                }
+            }
+            // All Notes Off
+            123 => {
+                self.held[inst].clear();
+                for v in self
+                    .voices
+                    .iter_mut()
+                    .filter(|v| v.active && v.inst_id == inst)
+                {
+                    v.held = false;
+                    v.env.note_off();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self, inst: usize, vel: u8) {
+        self.init_voice(inst, None, vel, None);
+    }
+
+    /// Sounding voices of an MPE member channel.
+    fn member_voices(&mut self, inst: usize, ch: u8) -> impl Iterator<Item = &mut Voice> {
+        self.voices
+            .iter_mut()
+            .filter(move |v| v.active && v.inst_id == inst && v.channel == Some(ch))
+    }
+
+    pub fn instrument(&self, inst: usize) -> &Instrument {
+        &self.instruments[inst]
+    }
+
+    /// Shared [Status], updated every [Synth::process].
+    pub fn status(&self) -> Arc<Status> {
+        self.status.clone()
+    }
+
+    /// Switch the tuning of `inst`, `None` for 12-EDO at [Synth::a4].
+    pub fn set_tuning(&mut self, inst: usize, tuning: Option<Tuning>) {
+        let tuning = tuning.map(Arc::new);
+        self.tunings.extend(tuning.clone());
+        self.instruments[inst].tuning = tuning;
+    }
+
+    /// Load a tuning to switch to at runtime with [Event::Tuning], returns
+    /// its index.
+    pub fn add_tuning(&mut self, tuning: Tuning) -> usize {
+        self.tunings.push(Arc::new(tuning));
+        self.tunings.len() - 1
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sr
+    }
+
+    /// Apply an event right away, bypassing the channel. Events for
+    /// instruments it doesn't have are dropped.
+    pub fn handle(&mut self, event: Event) {
+        if event.inst() >= self.instruments.len() {
+            return;
+        }
+        match event {
+            Event::NoteOn(inst, note, vel) => self.note_on(inst, note, vel),
+            Event::NoteOff(inst, note) => self.note_off(inst, note),
+            Event::Trigger(inst, vel) => self.trigger(inst, vel),
+            Event::ControlChange(inst, control, value) => self.control_change(inst, control, value),
+            Event::PitchBend(inst, value) => {
+                self.controls[inst].bend.target = value as f64 / 8192.0;
+            }
+            Event::Aftertouch(inst, pressure) => {
+                self.controls[inst].pressure.target = pressure as f64 / 127.0;
+            }
+            Event::PolyAftertouch(inst, note, pressure) => {
+                for v in self
+                    .voices
+                    .iter_mut()
+                    .filter(|v| v.active && v.inst_id == inst && v.note == note)
+                {
+                    v.pressure.target = pressure as f64 / 127.0;
+                }
+            }
+            Event::ProgramChange(..) => {}
+            Event::Param(inst, param) => self.instruments[inst].set(param),
+            Event::Tuning(inst, None) => self.instruments[inst].tuning = None,
+            Event::Tuning(inst, Some(index)) => {
+                if let Some(tuning) = self.tunings.get(index) {
+                    self.instruments[inst].tuning = Some(tuning.clone());
+                }
+            }
+            Event::MemberNoteOn(inst, ch, note, vel) => {
+                self.init_voice(inst, Some(note), vel, Some(ch))
+            }
+            Event::MemberNoteOff(inst, ch, note) => {
+                for v in self.voices.iter_mut().filter(|v| {
+                    v.active && v.inst_id == inst && v.channel == Some(ch) && v.note == note
+                }) {
+                    v.held = false;
+                    v.env.note_off();
+                }
+                for v in self.voices.iter_mut().filter(|v| {
+                    v.pending
+                        .is_some_and(|p| p.channel == Some(ch) && p.note == Some(note))
+                }) {
+                    v.pending = None;
+                }
+            }
+            Event::MemberBend(inst, ch, value) => {
+                let bend = value as f64 / 8192.0;
+                self.members[ch as usize].bend = bend;
+                for v in self.member_voices(inst, ch) {
+                    v.bend.target = bend;
+                }
+            }
+            Event::MemberPressure(inst, ch, pressure) => {
+                let pressure = pressure as f64 / 127.0;
+                self.members[ch as usize].pressure = pressure;
+                for v in self.member_voices(inst, ch) {
+                    v.pressure.target = pressure;
+                }
+            }
+            Event::MemberTimbre(inst, ch, value) => {
+                let timbre = value as f64 / 127.0;
+                self.members[ch as usize].timbre = timbre;
+                for v in self.member_voices(inst, ch) {
+                    v.timbre.target = timbre;
+                }
+            }
+        }
+    }
+
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let dt = 1.0 / self.sr;
+        let smooth = Smooth::coef(self.sr);
+
+        for _ in 0..128 {
+            let Ok(event) = self.rx.try_recv() else {
+                break;
+            };
+            self.handle(event);
+        }
+
+        let len = buf.len();
+        let master_gain = self.status.gain() as f64;
+
+        for sample in buf {
+            let mut mix = 0.0;
+
+            for controls in &mut self.controls {
+                controls.bend.next(smooth);
+                controls.mod_wheel.next(smooth);
+                controls.pressure.next(smooth);
+            }
+
+            for voice in self.voices.iter_mut().filter(|v| v.active) {
+                let mut amp = voice.env.next(dt);
+
+                if let Some(fade) = &mut voice.fade {
+                    *fade -= dt / STEAL_FADE;
+                    amp *= fade.max(0.0);
+
+                    if *fade <= 0.0 {
+                        match voice.pending.take() {
+                            Some(p) => {
+                                voice.start(
+                                    p.inst,
+                                    &self.instruments[p.inst],
+                                    p.note,
+                                    p.vel,
+                                    self.a4,
+                                );
+                                voice.set_channel(p.channel, &self.members);
+                            }
+                            None => voice.active = false,
+                        }
+                        continue;
+                    }
+                }
+
+                if voice.env.is_finished() {
+                    voice.active = false;
+                    continue;
+                }
+
+                if let Some(freq) = voice.slide.next(voice.freq) {
+                    voice.set_freq(freq);
+                }
+
+                let instrument = &self.instruments[voice.inst_id];
+                let controls = &self.controls[voice.inst_id];
+                voice.pressure.next(smooth);
+                voice.bend.next(smooth);
+                voice.timbre.next(smooth);
+
+                let mut pitch = controls.bend.value() * instrument.bend_range
+                    + voice.bend.value() * instrument.member_bend_range;
+                let (mut gain, mut cutoff, mut depth) = (1.0, 0.0, 1.0);
+                for &(source, target, amount) in &instrument.mods {
+                    let value = voice.source(source, controls) * amount;
+                    match target {
+                        Target::Pitch => pitch += value,
+                        Target::Amp => gain += value,
+                        Target::Cutoff => cutoff += value,
+                        Target::LfoDepth => depth += value,
+                    }
+                }
+
+                let lfo = voice.lfos.iter_mut().map(|lfo| lfo.next()).sum::<f64>() * depth;
+                let ratio = (1.0 + lfo) * (pitch / 12.0).exp2();
+
+                let mut sum = voice
+                    .oscs
+                    .iter_mut()
+                    .map(|osc| {
+                        osc.mod_ratio(ratio);
+                        osc.next()
+                    })
+                    .sum::<f64>();
+
+                if let (Some(filter), Some((base, _))) = (&mut voice.filter, instrument.filter) {
+                    sum = filter.next(sum, base * cutoff.exp2());
+                }
+
+                // mix += amp * (sum / voice.oscs.len() as f64);
+                let out = amp * voice.velocity * gain.max(0.0) * sum;
+                self.inst_mix[voice.inst_id] += out;
+                mix += out;
+            }
+
+            for (level, mix) in self.levels.iter_mut().zip(&mut self.inst_mix) {
+                level.add(HEADROOM * std::mem::take(mix));
+            }
+
+            let master = HEADROOM * master_gain * mix;
+            self.levels[self.instruments.len()].add(master);
+            *sample = self.limiter.next(master) as f32;
+            if let Some(sample) = self.decimator.next(*sample) {
+                self.status.scope.push(sample);
+            }
+        }
+
+        self.publish(len);
+    }
+
+    fn publish(&mut self, len: usize) {
+        let meters = self.status.instruments.iter().chain([&self.status.master]);
+        for (level, meter) in self.levels.iter_mut().zip(meters) {
+            level.publish(meter, len);
+        }
+        let limiting = self.limiter.gain() as f32;
+        self.status
+            .limiting
+            .store(limiting.to_bits(), Ordering::Relaxed);
+
+        let mut notes = [0u64; 2];
+        let mut voices = 0;
+        for v in self.voices.iter().filter(|v| v.active) {
+            voices += 1;
+            if v.held && self.instruments[v.inst_id].kind == preset::Kind::Pitched {
+                notes[v.note as usize / 64] |= 1 << (v.note % 64);
+            }
+        }
+
+        self.status.voices.store(voices, Ordering::Relaxed);
+        for (shared, notes) in self.status.notes.iter().zip(notes) {
+            shared.store(notes, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_voices_stay_free() {
+        let (_tx, rx) = mpsc::channel();
+        let pad = Instrument::builder().pitched().reserve(2).build();
+        let lead = Instrument::builder().pitched().build();
+        let mut synth = Synth::<4>::new(44100.0, rx, vec![pad, lead]);
+
+        for note in 60..65 {
+            synth.handle(Event::NoteOn(1, note, DEFAULT_VELOCITY));
+        }
+
+        // The lead stole from itself, the pad's two voices are untouched
+        assert!(synth.voices.iter().all(|v| !v.active || v.inst_id == 1));
+        assert_eq!(synth.voices.iter().filter(|v| !v.active).count(), 2);
+        assert_eq!(synth.voice_count(1), 2);
+    }
+
+    #[test]
+    fn unknown_instruments_are_dropped() {
+        let (_tx, rx) = mpsc::channel();
+        let mut synth = Synth::<4>::new(44100.0, rx, vec![Instrument::builder().build()]);
+
+        synth.handle(Event::NoteOn(9, 36, DEFAULT_VELOCITY));
+        synth.handle(Event::PitchBend(9, 100));
+        assert!(synth.voices.iter().all(|v| !v.active));
+    }
+
+    #[test]
+    fn switch_tunings() {
+        let (_tx, rx) = mpsc::channel();
+        let mut synth = Synth::<4>::new(44100.0, rx, vec![Instrument::builder().build()]);
+        let edo = synth.add_tuning(Tuning::edo(24));
+
+        synth.handle(Event::Tuning(0, Some(edo)));
+        assert_eq!(
+            synth.instrument(0).tuning.as_deref(),
+            Some(&Tuning::edo(24))
+        );
+        // Unknown tunings leave it as it is
+        synth.handle(Event::Tuning(0, Some(edo + 1)));
+        assert!(synth.instrument(0).tuning.is_some());
+        synth.handle(Event::Tuning(0, None));
+        assert!(synth.instrument(0).tuning.is_none());
+    }
+
+    #[test]
+    fn pitched_ignores_triggers() {
+        let (_tx, rx) = mpsc::channel();
+        let lead = Instrument::builder().pitched().build();
+        let mut synth = Synth::<4>::new(44100.0, rx, vec![lead]);
+
+        synth.handle(Event::Trigger(0, DEFAULT_VELOCITY));
+        assert_eq!(synth.voice_count(0), 0);
+    }
+}
@@ -0,0 +1,570 @@
+use std::sync::{Arc, mpsc};
+
+use ratatui::Frame;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Axis, Block, Chart, Dataset, Gauge, GraphType, Paragraph};
+
+use crate::env;
+use crate::kbd::{Action, Keyboard};
+use crate::osc::Waveform;
+use crate::preset::{Instrument, Param};
+use crate::scope;
+use crate::seq::{Pattern, Sequencer, Track};
+use crate::{Event, Status};
+
+/// Scope samples on screen, about 23 ms at the scope's 22.05 kHz.
+const SCOPE_WIDTH: usize = 512;
+
+/// One dB as a gain ratio.
+const DB: f32 = 1.122_018_5;
+
+/// Cells of a level meter bar.
+const METER_WIDTH: usize = 24;
+
+const WAVEFORMS: [Waveform; 5] = [
+    Waveform::Sine,
+    Waveform::Square,
+    Waveform::Triangle,
+    Waveform::Saw,
+    Waveform::Noise,
+];
+
+/// Which panel the arrow keys edit.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Instrument,
+    Sequencer,
+}
+
+/// Editing state of the TUI, it mirrors the instrument on the audio thread
+/// and sends it [Event::Param]s.
+pub struct App {
+    pub inst: usize,
+    shape: Option<env::Shape>, // `None` for multi-stage envelopes
+    oscs: Vec<(Waveform, f64)>,
+    selected: usize,
+    focus: Focus,
+    cursor: (usize, usize), // channel, step
+    status: Arc<Status>,
+    tx: mpsc::Sender<Event>,
+}
+
+impl App {
+    pub fn new(
+        inst: usize,
+        instrument: &Instrument,
+        status: Arc<Status>,
+        tx: mpsc::Sender<Event>,
+    ) -> Self {
+        let shape = match instrument.env {
+            env::Kind::Adsr(shape) => Some(shape),
+            env::Kind::Stages(_) => None,
+        };
+        Self {
+            inst,
+            shape,
+            oscs: instrument.oscs.clone(),
+            selected: 0,
+            focus: Focus::Instrument,
+            cursor: (0, 0),
+            status,
+            tx,
+        }
+    }
+
+    /// Rows of editable parameters, the ADSR ones first.
+    fn rows(&self) -> usize {
+        self.shape.map_or(0, |_| 4) + self.oscs.len()
+    }
+
+    /// Tab moves the focus between the instrument and the sequencer,
+    /// PageUp/PageDown set the master gain. Returns `false` for keys it
+    /// doesn't use, releases are never used.
+    ///
+    /// On the instrument Up/Down select a parameter, Left/Right change it and
+    /// Enter switches an oscillator's waveform. On the sequencer the arrows
+    /// move the cursor, Space toggles a step, `[`/`]` change the channel's
+    /// instrument, `,`/`.` slow it down and speed it up, `-`/`=` change the
+    /// tempo and Insert/Delete add and remove channels. Digits switch to a
+    /// pattern at the next bar, the one after the last copies the playing one.
+    pub fn key_event(&mut self, key: KeyEvent, seq: &mut Sequencer) -> bool {
+        if key.kind == KeyEventKind::Release {
+            return false;
+        }
+
+        match key.code {
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Focus::Instrument => Focus::Sequencer,
+                    Focus::Sequencer => Focus::Instrument,
+                };
+                return true;
+            }
+            // Master gain in dB steps, up to +12
+            KeyCode::PageUp => {
+                self.status.set_gain((self.status.gain() * DB).min(4.0));
+                return true;
+            }
+            KeyCode::PageDown => {
+                self.status.set_gain(self.status.gain() / DB);
+                return true;
+            }
+            _ => {}
+        }
+
+        match self.focus {
+            Focus::Instrument => self.edit_instrument(key.code),
+            Focus::Sequencer => self.edit_sequencer(key.code, seq),
+        }
+    }
+
+    fn edit_instrument(&mut self, code: KeyCode) -> bool {
+        let rows = self.rows().max(1);
+        match code {
+            KeyCode::Up => self.selected = (self.selected + rows - 1) % rows,
+            KeyCode::Down => self.selected = (self.selected + 1) % rows,
+            KeyCode::Left => self.adjust(-1.0, false),
+            KeyCode::Right => self.adjust(1.0, false),
+            KeyCode::Enter => self.adjust(0.0, true),
+            _ => return false,
+        }
+        true
+    }
+
+    fn edit_sequencer(&mut self, code: KeyCode, seq: &mut Sequencer) -> bool {
+        let instruments = self.status.instruments.len().max(1);
+        let (row, step) = &mut self.cursor;
+        let rows = seq.channels().len();
+        let len = seq
+            .channels()
+            .get(*row)
+            .map_or(seq.total_beats, |track| track.steps.len())
+            .max(1);
+        match code {
+            KeyCode::Up => *row = row.saturating_sub(1),
+            KeyCode::Down => *row = (*row + 1).min(rows.saturating_sub(1)),
+            KeyCode::Left => *step = (*step + len - 1) % len,
+            KeyCode::Right => *step = (*step + 1) % len,
+            KeyCode::Char(' ') => seq.toggle_step(*row, *step),
+            KeyCode::Char('[') | KeyCode::Char(']') => {
+                if let Some(track) = seq.channels_mut().and_then(|c| c.get_mut(*row)) {
+                    let by = if code == KeyCode::Char(']') {
+                        1
+                    } else {
+                        instruments - 1
+                    };
+                    track.inst = (track.inst + by) % instruments;
+                }
+            }
+            KeyCode::Char(',') | KeyCode::Char('.') => {
+                if let Some(track) = seq.channels_mut().and_then(|c| c.get_mut(*row)) {
+                    // Rates 1/8, .., 1/2, 1, 2, .., 8
+                    let (slower, faster) = match code == KeyCode::Char('.') {
+                        false => (&mut track.divide, &mut track.multiply),
+                        true => (&mut track.multiply, &mut track.divide),
+                    };
+                    if *faster > 1 {
+                        *faster -= 1;
+                    } else {
+                        *slower = (*slower + 1).min(8);
+                    }
+                }
+            }
+            KeyCode::Char('-') => seq.set_bpm((seq.bpm() - 1.0).max(20.0)),
+            KeyCode::Char('=') => seq.set_bpm((seq.bpm() + 1.0).min(300.0)),
+            KeyCode::Char(digit @ '1'..='9') => {
+                let index = digit as usize - '1' as usize;
+                if index == seq.patterns.len() {
+                    let mut pattern = seq
+                        .patterns
+                        .get(seq.playing())
+                        .cloned()
+                        .unwrap_or_else(|| Pattern::new("", 1));
+                    pattern.name = ((b'A' + index as u8) as char).to_string();
+                    seq.patterns.push(pattern);
+                }
+                seq.queue(index);
+            }
+            KeyCode::Insert => {
+                let inst = seq.channels().get(*row).map_or(0, |track| track.inst);
+                let len = seq.total_beats;
+                if let Some(channels) = seq.channels_mut() {
+                    channels.push(Track::new(inst, len));
+                    *row = channels.len() - 1;
+                }
+            }
+            KeyCode::Delete => {
+                seq.remove_channel(*row);
+                *row = (*row).min(seq.channels().len().saturating_sub(1));
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn adjust(&mut self, dir: f64, next_waveform: bool) {
+        // Times step geometrically so short ones stay precise
+        let time = |secs: f64| (secs.max(0.001) * 1.25f64.powf(dir)).clamp(0.001, 10.0);
+        let level = |x: f64| (x + 0.05 * dir).clamp(0.0, 1.0);
+
+        let mut row = self.selected;
+        let param = match &mut self.shape {
+            Some(shape) if row < 4 => match row {
+                0 => Param::Attack(update(&mut shape.attack, time)),
+                1 => Param::Decay(update(&mut shape.decay, time)),
+                2 => Param::Sustain(update(&mut shape.sustain, level)),
+                _ => Param::Release(update(&mut shape.release, time)),
+            },
+            shape => {
+                if shape.is_some() {
+                    row -= 4;
+                }
+                let Some((waveform, gain)) = self.oscs.get_mut(row) else {
+                    return;
+                };
+                if next_waveform {
+                    let i = WAVEFORMS.iter().position(|w| w == waveform).unwrap_or(0);
+                    *waveform = WAVEFORMS[(i + 1) % WAVEFORMS.len()];
+                } else {
+                    *gain = level(*gain);
+                }
+                Param::Osc(row, *waveform, *gain)
+            }
+        };
+
+        _ = self.tx.send(Event::Param(self.inst, param));
+    }
+
+    pub fn draw(&self, frame: &mut Frame, keyboard: &Keyboard, seq: &Sequencer) {
+        let meters = self.status.instruments.len() as u16 + 3;
+        let channels = seq.channels().len().max(1) as u16 + 2;
+        let [piano, middle, scope, help] = Layout::vertical([
+            Constraint::Length(6),
+            Constraint::Length((3 + meters + channels).max(9)),
+            Constraint::Min(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [wave, spectrum] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(scope);
+        let [instrument, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(middle);
+        let [voices, levels, grid] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(meters),
+            Constraint::Min(3),
+        ])
+        .areas(right);
+
+        self.draw_piano(frame, piano, keyboard);
+        self.draw_instrument(frame, instrument);
+        self.draw_voices(frame, voices);
+        self.draw_meters(frame, levels);
+        let cursor = (self.focus == Focus::Sequencer).then_some(self.cursor);
+        draw_grid(frame, grid, seq, cursor);
+        self.draw_scope(frame, wave, spectrum);
+
+        let keys = match self.focus {
+            Focus::Instrument => "↑↓ select · ←→ change · enter waveform",
+            Focus::Sequencer => {
+                "arrows move · space toggle · [ ] instrument · , . rate · - = tempo · 1-9 pattern · ins/del channel"
+            }
+        };
+        let text = format!(
+            " esc quit · tab focus · {keys} · bksp chord · pgup/pgdn gain · octave {:+} · transpose {:+}",
+            keyboard.octave, keyboard.transpose
+        );
+        frame.render_widget(Line::from(text).dim(), help);
+    }
+
+    fn draw_piano(&self, frame: &mut Frame, area: Rect, keyboard: &Keyboard) {
+        let block = Block::bordered().title(" keyboard ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        // Show whole octaves around the notes the computer keyboard plays
+        let width = (inner.width as i32).min(128);
+        let low = keyboard.note(0).unwrap_or(60) as i32;
+        let start = ((low - (width - 18) / 2).max(0) / 12 * 12)
+            .min(128 - width)
+            .max(0);
+
+        let mut labels = String::new();
+        let mut keys = Vec::new();
+        let mut letters = Vec::new();
+        for note in start..start + width {
+            let note = note as u8;
+            let black = matches!(note % 12, 1 | 3 | 6 | 8 | 10);
+            let color = if self.status.is_held(note) {
+                Color::LightGreen
+            } else if black {
+                Color::Black
+            } else {
+                Color::White
+            };
+
+            let column = (note as i32 - start) as usize;
+            if note.is_multiple_of(12) && labels.len() <= column {
+                labels.push_str(&" ".repeat(column - labels.len()));
+                labels.push_str(&format!("C{}", note as i32 / 12 - 1));
+            }
+            keys.push(Span::styled(" ", Style::new().bg(color)));
+
+            let letter = keyboard.keys.iter().find_map(|k| match (k.action, k.code) {
+                (Action::Note(offset), KeyCode::Char(c)) if keyboard.note(offset) == Some(note) => {
+                    Some(c)
+                }
+                _ => None,
+            });
+            letters.push(Span::raw(letter.map_or(" ".into(), String::from)));
+        }
+
+        let lines = vec![
+            Line::from(labels).dim(),
+            Line::from(keys.clone()),
+            Line::from(keys),
+            Line::from(letters).dim(),
+        ];
+        frame.render_widget(Paragraph::new(lines), inner);
+    }
+
+    fn draw_instrument(&self, frame: &mut Frame, area: Rect) {
+        let mut rows = Vec::new();
+        if let Some(shape) = self.shape {
+            rows.push(format!("attack   {:>8.3} s", shape.attack));
+            rows.push(format!("decay    {:>8.3} s", shape.decay));
+            rows.push(format!("sustain  {:>8.2}", shape.sustain));
+            rows.push(format!("release  {:>8.3} s", shape.release));
+        }
+        for (i, (waveform, gain)) in self.oscs.iter().enumerate() {
+            rows.push(format!("osc {i}    {:>8.2} {waveform:?}", gain));
+        }
+
+        let mut lines: Vec<Line> = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let line = Line::from(row);
+                if i == self.selected {
+                    line.reversed()
+                } else {
+                    line
+                }
+            })
+            .collect();
+        if self.shape.is_none() {
+            lines.insert(0, Line::from("multi-stage envelope").dim());
+        }
+
+        let mut block = Block::bordered().title(format!(" instrument {} ", self.inst));
+        if self.focus == Focus::Instrument {
+            block = block.border_style(Color::Cyan);
+        }
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_voices(&self, frame: &mut Frame, area: Rect) {
+        let voices = self
+            .status
+            .voices
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let capacity = self.status.capacity.max(1);
+        let gauge = Gauge::default()
+            .block(Block::bordered().title(" voices "))
+            .gauge_style(Color::Cyan)
+            .ratio((voices as f64 / capacity as f64).min(1.0))
+            .label(format!("{voices}/{capacity}"));
+        frame.render_widget(gauge, area);
+    }
+
+    /// Peak bars with RMS and clip counts, per instrument and the master.
+    fn draw_meters(&self, frame: &mut Frame, area: Rect) {
+        let names = (0..self.status.instruments.len()).map(|i| format!("inst {i:<3}"));
+        let meters = self.status.instruments.iter().chain([&self.status.master]);
+
+        let lines: Vec<Line> = names
+            .chain(["master  ".to_string()])
+            .zip(meters)
+            .map(|(name, meter)| {
+                let (peak, rms) = (db(meter.peak()), db(meter.rms()));
+                // -48..0 dB over the bar
+                let filled = (((peak + 48.0) / 48.0).clamp(0.0, 1.0) * METER_WIDTH as f32) as usize;
+                let color = match peak {
+                    p if p > -0.1 => Color::Red,
+                    p if p > -6.0 => Color::Yellow,
+                    _ => Color::Green,
+                };
+                let clips = meter.clips();
+                Line::from(vec![
+                    Span::raw(name),
+                    Span::styled("■".repeat(filled), color),
+                    Span::raw(" ".repeat(METER_WIDTH - filled)),
+                    Span::raw(format!(" {peak:>6.1} {rms:>6.1} dB ")),
+                    match clips {
+                        0 => Span::raw("").dim(),
+                        n => Span::styled(format!("clip {n}"), Color::Red),
+                    },
+                ])
+            })
+            .collect();
+
+        let title = format!(
+            " levels · gain {:+.1} dB · limit {:+.1} dB ",
+            db(self.status.gain()),
+            db(self.status.limiting())
+        );
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    /// Oscilloscope of the output next to its spectrum.
+    fn draw_scope(&self, frame: &mut Frame, wave: Rect, spectrum: Rect) {
+        let mut samples = Vec::new();
+        self.status.scope.read(&mut samples);
+        let rate = self.status.scope.sample_rate;
+
+        let points: Vec<(f64, f64)> = scope::trigger(&samples, SCOPE_WIDTH)
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| (i as f64, x as f64))
+            .collect();
+        let millis = SCOPE_WIDTH as f64 / rate * 1000.0;
+        let chart = Chart::new(vec![line(&points, Color::LightGreen)])
+            .block(Block::bordered().title(format!(" scope {millis:.0} ms ")))
+            .x_axis(Axis::default().bounds([0.0, SCOPE_WIDTH as f64]))
+            .y_axis(Axis::default().bounds([-1.0, 1.0]).labels(["-1", "0", "1"]));
+        frame.render_widget(chart, wave);
+
+        // Log frequency axis from 20 Hz to Nyquist, labels where they fall
+        let bounds = [20f64.log10(), (rate / 2.0).log10()];
+        let labels = (0..4).map(|i| {
+            let hz = 10f64.powf(bounds[0] + (bounds[1] - bounds[0]) * i as f64 / 3.0);
+            Span::raw(freq_label(hz)).dim()
+        });
+        let bins = scope::spectrum(&samples);
+        let hz = rate / 2.0 / bins.len().max(1) as f64;
+        let points: Vec<(f64, f64)> = bins
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(k, &db)| ((k as f64 * hz).log10(), db.max(-96.0)))
+            .collect();
+        let chart = Chart::new(vec![line(&points, Color::LightMagenta)])
+            .block(Block::bordered().title(" spectrum "))
+            .x_axis(Axis::default().bounds(bounds).labels(labels))
+            .y_axis(
+                Axis::default()
+                    .bounds([-96.0, 0.0])
+                    .labels(["-96", "-48", "0 dB"]),
+            );
+        frame.render_widget(chart, spectrum);
+    }
+}
+
+/// `hz` in two significant digits, like `160` or `1.3k`.
+fn freq_label(hz: f64) -> String {
+    let digits = hz.log10().floor() as i32 - 1;
+    let hz = (hz / 10f64.powi(digits)).round() * 10f64.powi(digits);
+    match hz >= 1000.0 {
+        true => format!("{}k", hz / 1000.0),
+        false => format!("{hz}"),
+    }
+}
+
+/// Linear level in dB, floored at -96.
+fn db(level: f32) -> f32 {
+    (20.0 * level.log10()).max(-96.0)
+}
+
+fn line(points: &[(f64, f64)], color: Color) -> Dataset<'_> {
+    Dataset::default()
+        .marker(Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(color)
+        .data(points)
+}
+
+/// Set `value` through `f`, returning the new value.
+fn update(value: &mut f64, f: impl Fn(f64) -> f64) -> f64 {
+    *value = f(*value);
+    *value
+}
+
+/// The sequencer tracks, a row of steps each, with the playing step lit.
+/// `x` triggers, `o` notes, upper case for accents.
+fn draw_grid(frame: &mut Frame, area: Rect, seq: &Sequencer, cursor: Option<(usize, usize)>) {
+    let position = seq.pattern_position();
+
+    let lines: Vec<Line> = seq
+        .channels()
+        .iter()
+        .enumerate()
+        .map(|(row, track)| {
+            let playing = track.index(position) as usize % track.steps.len().max(1);
+            let mut spans = vec![Span::raw(format!("inst {:<3}", track.inst))];
+            for (i, step) in track.steps.iter().enumerate() {
+                let mark = match step {
+                    None => " .",
+                    Some(step) => match (step.note, step.velocity >= 120) {
+                        (None, false) => " x",
+                        (None, true) => " X",
+                        (Some(_), false) => " o",
+                        (Some(_), true) => " O",
+                    },
+                };
+                let mut style = Style::new();
+                if i.is_multiple_of(seq.sub_beats as usize) {
+                    style = style.bold();
+                }
+                if i == playing {
+                    style = style.bg(Color::Yellow).fg(Color::Black);
+                }
+                if cursor == Some((row, i)) {
+                    style = style.reversed();
+                }
+                spans.push(Span::styled(mark, style));
+            }
+            Line::from(spans)
+        })
+        .collect();
+
+    let name = |index: usize| seq.patterns.get(index).map_or("", |p| p.name.as_str());
+    let mut pattern = format!("pattern {}", name(seq.playing()));
+    if let Some(next) = seq.queued() {
+        pattern += &format!(" → {}", name(next));
+    }
+    let mut block = Block::bordered().title(format!(
+        " sequencer {} bpm · swing {:.0}% · {pattern} ",
+        seq.bpm(),
+        seq.swing * 100.0
+    ));
+    if let Some((row, i)) = cursor {
+        block = block.border_style(Color::Cyan);
+        if let Some(track) = seq.channels().get(row) {
+            let mut title = format!(" rate {}:{} ", track.multiply, track.divide);
+            if let Some(Some(step)) = track.steps.get(i) {
+                let note = step
+                    .note
+                    .map_or("trigger".to_string(), |note| format!("note {note}"));
+                title += &format!(
+                    "· {note} · vel {} · gate {:.2} · prob {:.0}% · ratchet {} · offset {:+.2} ",
+                    step.velocity,
+                    step.gate,
+                    step.probability * 100.0,
+                    step.ratchet,
+                    step.offset
+                );
+            }
+            block = block.title_bottom(title);
+        }
+    }
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
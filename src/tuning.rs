@@ -0,0 +1,301 @@
+use std::io;
+use std::path::Path;
+
+use crate::{Hz, invalid};
+
+/// Lines of a Scala file, without `!` comments.
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+/// A Scala pitch, cents when it has a dot, a ratio `n/d` or integer otherwise.
+fn parse_pitch(line: &str) -> io::Result<f64> {
+    let token = line
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| invalid("missing pitch"))?;
+    if token.contains('.') {
+        return token.parse().map_err(|_| invalid("invalid cents"));
+    }
+    let (num, den) = token.split_once('/').unwrap_or((token, "1"));
+    let num: f64 = num.parse().map_err(|_| invalid("invalid ratio"))?;
+    let den: f64 = den.parse().map_err(|_| invalid("invalid ratio"))?;
+    if num <= 0.0 || den <= 0.0 {
+        return Err(invalid("ratio must be positive"));
+    }
+    Ok(1200.0 * (num / den).log2())
+}
+
+/// How MIDI notes map onto scale degrees, a Scala `.kbm` keyboard mapping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub first: u8,
+    pub last: u8,
+    /// Note playing scale degree 0.
+    pub middle: u8,
+    /// Note tuned to a frequency, `None` for A4 (69) at the synth's A4.
+    pub reference: Option<(u8, f64)>, // note, Hz
+    /// Scale degree the mapping repeats at, 0 for the scale's size.
+    pub period: i32,
+    /// Degree per key in the mapping, `None` for unmapped keys, empty
+    /// maps every note to the next degree.
+    pub keys: Vec<Option<i32>>,
+}
+
+impl Default for Mapping {
+    fn default() -> Self {
+        Self {
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference: None,
+            period: 0,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl Mapping {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut lines = lines(text);
+        let mut next = || {
+            lines
+                .next()
+                .map(str::trim)
+                .ok_or_else(|| invalid("truncated kbm"))
+        };
+        let mut number = || -> io::Result<f64> {
+            let line = next()?;
+            let token = line.split_whitespace().next().unwrap_or_default();
+            token.parse().map_err(|_| invalid("invalid kbm number"))
+        };
+
+        let size = number()? as usize;
+        let first = number()? as u8;
+        let last = number()? as u8;
+        let middle = number()? as u8;
+        let reference = (number()? as u8, number()?);
+        let period = number()? as i32;
+
+        let mut keys = Vec::with_capacity(size);
+        for _ in 0..size {
+            // Trailing keys may be left out, they are unmapped
+            let Ok(line) = next() else { break };
+            keys.push(match line.split_whitespace().next() {
+                Some("x") | None => None,
+                Some(token) => Some(token.parse().map_err(|_| invalid("invalid kbm key"))?),
+            });
+        }
+        keys.resize(size, None);
+
+        let mapping = Self {
+            first,
+            last,
+            middle,
+            reference: Some(reference),
+            period,
+            keys,
+        };
+        // Everything is tuned relative to the reference, it needs a degree
+        if mapping.degree(reference.0, 1).is_none() {
+            return Err(invalid("kbm reference note is unmapped"));
+        }
+        Ok(mapping)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Scale degree of `note` relative to [Mapping::middle] in a scale of
+    /// `scale_size` degrees, `None` when unmapped.
+    fn degree(&self, note: u8, scale_size: i32) -> Option<i32> {
+        if note < self.first || note > self.last {
+            return None;
+        }
+        let steps = note as i32 - self.middle as i32;
+        if self.keys.is_empty() {
+            return Some(steps);
+        }
+        let size = self.keys.len() as i32;
+        let key = self.keys[steps.rem_euclid(size) as usize]?;
+        let period = if self.period == 0 {
+            scale_size
+        } else {
+            self.period
+        };
+        Some(steps.div_euclid(size) * period + key)
+    }
+}
+
+/// A scale and keyboard mapping resolving MIDI notes to frequencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// Cents of each degree above the root, the last one is the period.
+    pub cents: Vec<f64>,
+    pub mapping: Mapping,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::edo(12)
+    }
+}
+
+impl Tuning {
+    /// `n` equal divisions of the octave.
+    pub fn edo(n: usize) -> Self {
+        Self::cents((1..=n).map(|i| 1200.0 * i as f64 / n as f64).collect())
+    }
+
+    /// Degrees as frequency ratios to the root, like `[9.0 / 8.0, 5.0 / 4.0, .., 2.0]`
+    /// for just intonation, the last one is the period.
+    pub fn ratios(ratios: &[f64]) -> Self {
+        Self::cents(ratios.iter().map(|ratio| 1200.0 * ratio.log2()).collect())
+    }
+
+    pub fn cents(cents: Vec<f64>) -> Self {
+        assert!(!cents.is_empty(), "a scale needs at least one degree");
+        Self {
+            cents,
+            mapping: Mapping::default(),
+        }
+    }
+
+    /// Parse a Scala `.scl` scale.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut lines = lines(text);
+        let _description = lines.next().ok_or_else(|| invalid("missing description"))?;
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|token| token.parse().ok())
+            .ok_or_else(|| invalid("invalid note count"))?;
+
+        let cents = lines
+            .take(count)
+            .map(|line| parse_pitch(line.trim()))
+            .collect::<io::Result<Vec<_>>>()?;
+        if cents.len() != count || count == 0 {
+            return Err(invalid("missing pitches"));
+        }
+
+        Ok(Self::cents(cents))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn with_mapping(mut self, mapping: Mapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Cents of scale `degree` above degree 0, across periods.
+    fn degree_cents(&self, degree: i32) -> f64 {
+        let n = self.cents.len() as i32;
+        let period = self.cents[n as usize - 1];
+        let step = degree.rem_euclid(n);
+        let base = if step == 0 {
+            0.0
+        } else {
+            self.cents[step as usize - 1]
+        };
+        degree.div_euclid(n) as f64 * period + base
+    }
+
+    /// Frequency of `note` with A4 at `a4` Hz unless the mapping has its own
+    /// reference, `None` for unmapped notes or an unmapped reference.
+    pub fn freq(&self, note: u8, a4: f64) -> Option<Hz> {
+        let (ref_note, ref_freq) = self.mapping.reference.unwrap_or((69, a4));
+        let size = self.cents.len() as i32;
+        let cents = self.degree_cents(self.mapping.degree(note, size)?);
+        let ref_cents = self.degree_cents(self.mapping.degree(ref_note, size)?);
+        Some(Hz(ref_freq * ((cents - ref_cents) / 1200.0).exp2()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCL: &str = "! just.scl
+!
+Just major, 5-limit
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 1200.0 ! octave
+";
+
+    // White keys only, C4 is degree 0 and A4 is 440 Hz
+    const KBM: &str = "! white.kbm
+12
+0
+127
+60
+69
+440.0
+7
+! mapping
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn parse_scl() {
+        let tuning = Tuning::parse(SCL).unwrap();
+        assert_eq!(tuning.cents.len(), 7);
+        assert!(close(tuning.cents[0], 1200.0 * (9.0f64 / 8.0).log2()));
+        assert!(close(tuning.cents[3], 1200.0 * 1.5f64.log2()));
+        assert!(close(tuning.cents[6], 1200.0));
+
+        assert!(Tuning::parse("missing pitches\n 3\n 3/2\n").is_err());
+        assert!(Tuning::parse("bad ratio\n 1\n 0/2\n").is_err());
+    }
+
+    #[test]
+    fn parse_kbm() {
+        let mapping = Mapping::parse(KBM).unwrap();
+        assert_eq!((mapping.first, mapping.last, mapping.middle), (0, 127, 60));
+        assert_eq!(mapping.reference, Some((69, 440.0)));
+        assert_eq!(mapping.period, 7);
+        assert_eq!(mapping.keys.len(), 12);
+        assert_eq!(mapping.keys[1], None);
+        assert_eq!(mapping.keys[11], Some(6));
+
+        let tuning = Tuning::parse(SCL).unwrap().with_mapping(mapping);
+        let freq = |note| tuning.freq(note, 432.0).map(|Hz(f)| f);
+        assert!(close(freq(69).unwrap(), 440.0));
+        // A just major sixth below A4
+        assert!(close(freq(60).unwrap(), 440.0 * 3.0 / 5.0));
+        assert!(close(freq(72).unwrap(), 440.0 * 6.0 / 5.0));
+        assert_eq!(freq(61), None);
+    }
+
+    #[test]
+    fn unmapped_reference() {
+        let kbm = KBM.replace("69\n440.0", "70\n440.0");
+        assert!(Mapping::parse(&kbm).is_err());
+    }
+}
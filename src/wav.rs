@@ -0,0 +1,31 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Write mono samples as a 16-bit PCM WAV file.
+pub fn write(path: impl AsRef<Path>, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let data_len = samples.len() as u32 * 2;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&1u16.to_le_bytes())?; // mono
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per sec
+    w.write_all(&2u16.to_le_bytes())?; // block align
+    w.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    for &sample in samples {
+        let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        w.write_all(&pcm.to_le_bytes())?;
+    }
+
+    w.flush()
+}